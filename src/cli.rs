@@ -0,0 +1,86 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage:
+  simple_linux_evdev_controller_muxer [OPTIONS] [DEVICE NAME]...
+  simple_linux_evdev_controller_muxer monitor [OPTIONS] [DEVICE NAME]...
+  simple_linux_evdev_controller_muxer list-devices [--json]
+
+Merges input devices into one virtual controller. Device names given here replace the
+configured devices in order, extra names are added as further devices.
+
+Options:
+  --config <PATH>    Config file (default: ~/.config/controller-muxer/config.toml)
+  --device <NAME>    Add a device matched by name, may be repeated
+  --monitor          Show a live view of every device's input and the merged output
+  -h, --help         Show this help
+
+Commands:
+  monitor            Same as --monitor
+  list-devices       Print every input device with its IDs, axes and buttons
+";
+
+pub enum Command {
+    Run(RunArgs),
+    ListDevices { json: bool },
+    Help,
+}
+
+#[derive(Default)]
+pub struct RunArgs {
+    pub config_path: Option<PathBuf>,
+    /// Positional names, replacing the configured devices in order.
+    pub device_names: Vec<String>,
+    /// Names given with `--device`, always added as new devices.
+    pub extra_devices: Vec<String>,
+    pub monitor: bool,
+}
+
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Command, Box<dyn Error>> {
+    let mut run = RunArgs::default();
+    let mut first = true;
+    while let Some(arg) = args.next() {
+        if first && arg == "list-devices" {
+            return parse_list_devices(args);
+        }
+        if first && arg == "monitor" {
+            run.monitor = true;
+            first = false;
+            continue;
+        }
+        first = false;
+
+        if arg == "-h" || arg == "--help" {
+            return Ok(Command::Help);
+        } else if arg == "--monitor" {
+            run.monitor = true;
+        } else if arg == "--config" {
+            let path = args.next().ok_or("--config requires a path")?;
+            run.config_path = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--config=") {
+            run.config_path = Some(PathBuf::from(path));
+        } else if arg == "--device" {
+            run.extra_devices.push(args.next().ok_or("--device requires a device name")?);
+        } else if let Some(name) = arg.strip_prefix("--device=") {
+            run.extra_devices.push(name.to_string());
+        } else if arg.starts_with("--") {
+            return Err(format!("Unknown option '{}', see --help", arg).into());
+        } else {
+            run.device_names.push(arg);
+        }
+    }
+    Ok(Command::Run(run))
+}
+
+fn parse_list_devices(args: impl Iterator<Item = String>) -> Result<Command, Box<dyn Error>> {
+    let mut json = false;
+    for arg in args {
+        match arg.as_str() {
+            "--json" => json = true,
+            "-h" | "--help" => return Ok(Command::Help),
+            _ => return Err(format!("Unknown argument '{}' for list-devices, see --help", arg).into()),
+        }
+    }
+    Ok(Command::ListDevices { json })
+}
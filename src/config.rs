@@ -0,0 +1,712 @@
+use crate::mapping::{self, Mapping};
+use crate::mux::AxisPolicy;
+use crate::shaping::Curve;
+use evdev::{AbsoluteAxisCode, BusType, FFEffectCode, InputId, KeyCode};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::{env, fs};
+
+// --- DEFAULTS (used when no config file exists) ---
+const DEFAULT_DEVICES: &[&str] = &["Xbox Wireless Controller", "RealityRunner Treadmill Sensor"];
+const DEFAULT_VIRTUAL_NAME: &str = "Muxed Controller";
+// Same IDs uinput devices get when none are set.
+const DEFAULT_INPUT_ID: (u16, u16, u16) = (0x1234, 0x5678, 0x0111);
+const DEFAULT_FF_EFFECTS: &[FFEffectCode] = &[FFEffectCode::FF_RUMBLE];
+// Axis-to-button thresholds, as fractions of the way from rest to the end of the axis.
+const DEFAULT_PRESS: f64 = 0.5;
+const DEFAULT_RELEASE: f64 = 0.4;
+const DEFAULT_TREADMILL_STICK: &str = "ABS_Y";
+const DEFAULT_STRAFE_AXIS: &str = "ABS_X";
+const DEFAULT_TREADMILL_SMOOTHING: f64 = 0.15;
+const DEFAULT_RUN_THRESHOLD: f64 = 0.8;
+const MAX_MEDIAN: usize = 64;
+// ---------------------
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default)]
+    pub virtual_device: VirtualDeviceConfig,
+    #[serde(default)]
+    pub merge: MergeConfig,
+    #[serde(default = "default_devices")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct VirtualDeviceConfig {
+    /// Built-in identity of a well known controller, individual keys below override it.
+    pub preset: Option<IdentityPreset>,
+    pub name: Option<String>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    pub version: Option<u16>,
+    /// Kernel bus name such as `BUS_USB` or `BUS_BLUETOOTH` (`usb` and `bluetooth` work too).
+    pub bustype: Option<String>,
+    /// Physical path reported to applications, e.g. `usb-0000:00:14.0-1/input0`.
+    pub phys: Option<String>,
+    /// Force feedback effects offered to games, e.g. `["FF_RUMBLE", "FF_PERIODIC"]`. Defaults to rumble,
+    /// an empty list turns force feedback off.
+    pub ff_effects: Option<Vec<String>>,
+    /// Declare every key and axis the first device supports (plus those of the others) instead of
+    /// only the standard gamepad layout.
+    pub mirror_capabilities: bool,
+}
+
+/// Identities of real controllers, so SDL's game controller database and games recognise the virtual device.
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub enum IdentityPreset {
+    #[serde(rename = "xbox-360")]
+    Xbox360,
+    #[serde(rename = "xbox-one")]
+    XboxOne,
+}
+
+impl IdentityPreset {
+    /// Name, bus type, vendor, product and version as the `xpad` driver reports them.
+    fn identity(self) -> (&'static str, BusType, u16, u16, u16) {
+        match self {
+            IdentityPreset::Xbox360 => ("Microsoft X-Box 360 pad", BusType::BUS_USB, 0x045e, 0x028e, 0x0114),
+            IdentityPreset::XboxOne => ("Microsoft X-Box One S pad", BusType::BUS_USB, 0x045e, 0x02ea, 0x0301),
+        }
+    }
+}
+
+/// The resolved identity of the virtual device.
+pub struct Identity {
+    pub name: String,
+    pub input_id: InputId,
+    pub phys: Option<String>,
+}
+
+impl VirtualDeviceConfig {
+    pub fn identity(&self) -> Identity {
+        let (default_vendor, default_product, default_version) = DEFAULT_INPUT_ID;
+        let (name, bus, vendor, product, version) = match self.preset {
+            Some(preset) => preset.identity(),
+            None => (DEFAULT_VIRTUAL_NAME, BusType::BUS_USB, default_vendor, default_product, default_version),
+        };
+        let bus = self.bustype.as_deref().and_then(parse_bus_type).unwrap_or(bus);
+        Identity {
+            name: self.name.clone().unwrap_or_else(|| name.to_string()),
+            input_id: InputId::new(
+                bus,
+                self.vendor.unwrap_or(vendor),
+                self.product.unwrap_or(product),
+                self.version.unwrap_or(version),
+            ),
+            phys: self.phys.clone(),
+        }
+    }
+}
+
+impl VirtualDeviceConfig {
+    pub fn ff_effects(&self) -> Vec<FFEffectCode> {
+        match &self.ff_effects {
+            Some(names) => names.iter().filter_map(|name| name.parse().ok()).collect(),
+            None => DEFAULT_FF_EFFECTS.to_vec(),
+        }
+    }
+}
+
+fn parse_bus_type(name: &str) -> Option<BusType> {
+    let name = name.to_ascii_uppercase();
+    name.parse().or_else(|_| format!("BUS_{}", name).parse()).ok()
+}
+
+/// How axes driven by several sources are combined.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct MergeConfig {
+    /// Policy for every axis not listed in `axes`.
+    pub policy: AxisPolicy,
+    /// Per-axis overrides keyed by axis name, e.g. `ABS_Y = "sum-and-clamp"`.
+    pub axes: HashMap<String, AxisPolicy>,
+}
+
+impl MergeConfig {
+    pub fn policy_for(&self, axis: AbsoluteAxisCode) -> AxisPolicy {
+        self.axes.iter()
+            .find(|(name, _)| name.parse::<AbsoluteAxisCode>().ok() == Some(axis))
+            .map_or(self.policy, |(_, policy)| *policy)
+    }
+}
+
+/// One source device. The matching keys (`name`, `exact_name`, `name_regex`, `vendor`, `product`,
+/// `uniq`, `phys` and `path`) can be combined, a device has to satisfy all of those that are set.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// Substring matched against the device name reported by the kernel.
+    pub name: Option<String>,
+    pub exact_name: Option<String>,
+    pub name_regex: Option<String>,
+    pub vendor: Option<u16>,
+    pub product: Option<u16>,
+    /// Serial number or Bluetooth MAC address.
+    pub uniq: Option<String>,
+    /// Physical path, tells apart identical devices on different ports.
+    pub phys: Option<String>,
+    /// Device node such as `/dev/input/by-id/usb-...-event-joystick`, symlinks are followed.
+    pub path: Option<PathBuf>,
+    /// Rank for the `priority-ordered` merge policy, higher wins. Defaults to list order.
+    pub priority: Option<i32>,
+    /// Play the games' force feedback effects on this device, if it supports force feedback.
+    #[serde(default = "default_true")]
+    pub force_feedback: bool,
+    /// Grab the device so only the muxer sees its events. Off, other programs keep reading it too.
+    #[serde(default = "default_true")]
+    pub grab: bool,
+    /// Take away everyone else's access to the device node while it is in use, so games that ignore
+    /// the grab (or run while it is off) can't open it. Needs permission to chmod the node.
+    #[serde(default)]
+    pub hide: bool,
+    /// Button remaps applied before merging, e.g. `BTN_SOUTH = "BTN_TR"`. `"none"` drops a button.
+    #[serde(default)]
+    pub buttons: HashMap<String, ButtonRule>,
+    /// Axis routing applied before merging, keyed by the device's axis name.
+    #[serde(default)]
+    pub axes: HashMap<String, AxisRule>,
+    /// Axis pairs that share a radial deadzone.
+    #[serde(default)]
+    pub sticks: Vec<StickConfig>,
+    /// Turns the device into a treadmill whose belt speed pushes a stick forward.
+    #[serde(default)]
+    pub treadmill: Option<TreadmillConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TreadmillConfig {
+    /// The sensor axis reporting the belt speed.
+    pub axis: String,
+    /// Virtual axis pushed forward, `ABS_Y` by default.
+    pub stick: Option<String>,
+    /// Push the stick towards its maximum instead of its minimum (up on `ABS_Y`).
+    #[serde(default)]
+    pub invert: bool,
+    /// Raw sensor value below which you are standing still, the axis minimum by default.
+    pub min_speed: Option<i32>,
+    /// Raw sensor value that pushes the stick all the way, the axis maximum by default.
+    pub max_speed: Option<i32>,
+    /// Time constant of the moving average smoothing the speed, in seconds (0 turns it off).
+    pub smoothing: Option<f64>,
+    /// Largest change of the stick per second (1 being full deflection) while speeding up.
+    pub ramp_up: Option<f64>,
+    /// The same while slowing down.
+    pub ramp_down: Option<f64>,
+    /// Stick deflection (0 to 1) from which you are running.
+    pub run_threshold: Option<f64>,
+    /// Button held while running, e.g. `BTN_THUMBL` for games that sprint on a stick click.
+    pub sprint_button: Option<String>,
+    /// Response curve applied to the speed before smoothing.
+    pub curve: Option<Curve>,
+    /// Let the other devices steer: their stick sets the direction, the treadmill how far it is pushed.
+    #[serde(default)]
+    pub heading: bool,
+    /// The stick's sideways axis for `heading`, `ABS_X` by default.
+    pub strafe_axis: Option<String>,
+}
+
+impl TreadmillConfig {
+    pub fn stick(&self) -> &str {
+        self.stick.as_deref().unwrap_or(DEFAULT_TREADMILL_STICK)
+    }
+
+    pub fn strafe_axis(&self) -> &str {
+        self.strafe_axis.as_deref().unwrap_or(DEFAULT_STRAFE_AXIS)
+    }
+
+    pub fn smoothing(&self) -> f64 {
+        self.smoothing.unwrap_or(DEFAULT_TREADMILL_SMOOTHING)
+    }
+
+    pub fn run_threshold(&self) -> f64 {
+        self.run_threshold.unwrap_or(DEFAULT_RUN_THRESHOLD)
+    }
+}
+
+/// Two source axes that form one stick, e.g. `x = "ABS_X"` and `y = "ABS_Y"`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StickConfig {
+    pub x: String,
+    pub y: String,
+    /// Radial deadzone around the centre, as a fraction of the full deflection.
+    #[serde(default)]
+    pub deadzone: f64,
+    /// Radial deadzone at the edge, beyond which the stick counts as fully deflected.
+    #[serde(default)]
+    pub outer_deadzone: f64,
+    /// Response curve on how far the stick is pushed, in any direction.
+    #[serde(default)]
+    pub curve: Option<Curve>,
+}
+
+/// What one source button does: press the named virtual button (`"none"` to drop it) or hold an
+/// axis at a value.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ButtonRule {
+    Target(String),
+    Axis(ButtonAxis),
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ButtonAxis {
+    pub axis: String,
+    /// Where the axis goes while the button is held, in the virtual axis's units.
+    pub value: i32,
+}
+
+/// Where one source axis goes: either just the virtual axis name (`"ABS_Y"`, `"none"` to drop it)
+/// or a table of options.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum AxisRule {
+    Target(String),
+    Options(AxisOptions),
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AxisOptions {
+    /// Virtual axis to drive, defaults to the axis of the same name.
+    pub to: Option<String>,
+    /// Flip the axis around the middle of its range.
+    pub invert: bool,
+    /// Press this virtual button instead of driving an axis.
+    pub button: Option<String>,
+    /// How far (0 to 1) from rest the axis has to go to press `button`. Defaults to 0.5.
+    pub press: Option<f64>,
+    /// How far back it has to come to release it again, below `press`. Defaults to 0.4.
+    pub release: Option<f64>,
+    /// Axial deadzone around rest, as a fraction of the way to the end of the axis.
+    pub deadzone: Option<f64>,
+    /// Axial deadzone at the end, beyond which the axis counts as fully deflected.
+    pub outer_deadzone: Option<f64>,
+    /// Response curve, applied after the deadzones.
+    pub curve: Option<Curve>,
+    /// Evens out a noisy axis before the deadzones.
+    pub filter: Option<FilterConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct FilterConfig {
+    /// Median of the last this many readings, dropping single spikes.
+    pub median: Option<usize>,
+    /// Time constant of a low-pass (exponential moving average), in seconds.
+    pub smoothing: Option<f64>,
+    /// Largest change per second, 1 being full deflection.
+    pub rate_limit: Option<f64>,
+    /// Smallest change (0 to 1) worth sending, smaller ones are held back until they add up.
+    pub min_delta: Option<f64>,
+}
+
+impl AxisOptions {
+    pub fn press(&self) -> f64 {
+        self.press.unwrap_or(DEFAULT_PRESS)
+    }
+
+    pub fn release(&self) -> f64 {
+        self.release.unwrap_or(DEFAULT_RELEASE)
+    }
+}
+
+impl AxisRule {
+    pub fn options(&self) -> AxisOptions {
+        match self {
+            AxisRule::Target(to) => AxisOptions { to: Some(to.clone()), ..AxisOptions::default() },
+            AxisRule::Options(options) => options.clone(),
+        }
+    }
+}
+
+impl DeviceConfig {
+    pub fn named(name: impl Into<String>) -> Self {
+        Self {
+            name: Some(name.into()),
+            exact_name: None,
+            name_regex: None,
+            vendor: None,
+            product: None,
+            uniq: None,
+            phys: None,
+            path: None,
+            priority: None,
+            force_feedback: true,
+            grab: true,
+            hide: false,
+            buttons: HashMap::new(),
+            axes: HashMap::new(),
+            sticks: Vec::new(),
+            treadmill: None,
+        }
+    }
+
+    /// Replaces whatever selected the device with a plain name match, keeping all other settings.
+    pub fn match_name(&mut self, name: impl Into<String>) {
+        self.name = Some(name.into());
+        self.exact_name = None;
+        self.name_regex = None;
+        self.vendor = None;
+        self.product = None;
+        self.uniq = None;
+        self.phys = None;
+        self.path = None;
+    }
+
+    fn has_matcher(&self) -> bool {
+        self.name.is_some() || self.exact_name.is_some() || self.name_regex.is_some()
+            || self.vendor.is_some() || self.product.is_some() || self.uniq.is_some() || self.phys.is_some()
+            || self.path.is_some()
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            virtual_device: VirtualDeviceConfig::default(),
+            merge: MergeConfig::default(),
+            devices: default_devices(),
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config from `path`, or from the default location when no path is given.
+    /// A missing file at the default location falls back to the built-in defaults.
+    pub fn load(path: Option<&Path>) -> Result<Self, Box<dyn Error>> {
+        let (path, explicit) = match path {
+            Some(path) => (path.to_path_buf(), true),
+            None => match default_path() {
+                Some(path) => (path, false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        if !explicit && !path.exists() {
+            println!("No config file at {}, using built-in defaults.", path.display());
+            return Ok(Self::default());
+        }
+
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("Could not read config file {}: {}", path.display(), e))?;
+        let config: Config = toml::from_str(&text)
+            .map_err(|e| format!("Invalid config file {}:\n{}", path.display(), e))?;
+        config.validate()
+            .map_err(|e| format!("Invalid config file {}: {}", path.display(), e))?;
+
+        println!("Loaded config from {}", path.display());
+        Ok(config)
+    }
+
+    /// Checks the values serde cannot, reporting the offending key.
+    pub fn validate(&self) -> Result<(), String> {
+        let virtual_device = &self.virtual_device;
+        if virtual_device.name.as_ref().is_some_and(|name| name.trim().is_empty()) {
+            return Err("`virtual_device.name` must not be empty".to_string());
+        }
+        if let Some(bus) = &virtual_device.bustype
+            && parse_bus_type(bus).is_none() {
+            return Err(format!("`virtual_device.bustype` \"{}\" is not a known bus type (expected e.g. BUS_USB)", bus));
+        }
+        if virtual_device.phys.as_ref().is_some_and(|phys| phys.contains('\0')) {
+            return Err("`virtual_device.phys` must not contain NUL characters".to_string());
+        }
+        for (i, name) in virtual_device.ff_effects.iter().flatten().enumerate() {
+            if name.parse::<FFEffectCode>().is_err() {
+                return Err(format!("`virtual_device.ff_effects[{}]` \"{}\" is not a known effect (expected e.g. FF_RUMBLE)", i, name));
+            }
+        }
+        if self.devices.is_empty() {
+            return Err("`devices` must declare at least one input device".to_string());
+        }
+        for name in self.merge.axes.keys() {
+            if name.parse::<AbsoluteAxisCode>().is_err() {
+                return Err(format!("`merge.axes.{}` is not a known axis (expected e.g. ABS_X)", name));
+            }
+        }
+        if self.devices.iter().filter(|device| device.treadmill.as_ref().is_some_and(|treadmill| treadmill.heading)).count() > 1 {
+            return Err("only one `devices[].treadmill` can set `heading`".to_string());
+        }
+        for (i, device) in self.devices.iter().enumerate() {
+            if !device.has_matcher() {
+                return Err(format!("`devices[{}]` needs at least one of name, exact_name, name_regex, vendor, product, uniq, phys or path", i));
+            }
+            for (key, value) in [("name", &device.name), ("exact_name", &device.exact_name), ("uniq", &device.uniq), ("phys", &device.phys)] {
+                if value.as_ref().is_some_and(|value| value.trim().is_empty()) {
+                    return Err(format!("`devices[{}].{}` must not be empty", i, key));
+                }
+            }
+            if let Some(regex) = &device.name_regex
+                && let Err(e) = Regex::new(regex) {
+                return Err(format!("`devices[{}].name_regex` is not a valid regex: {}", i, e));
+            }
+            for (from, rule) in &device.buttons {
+                if from.parse::<KeyCode>().is_err() {
+                    return Err(format!("`devices[{}].buttons.{}` is not a known button (expected e.g. BTN_SOUTH)", i, from));
+                }
+                match rule {
+                    ButtonRule::Target(to) if mapping::parse_target(to).is_none() => {
+                        return Err(format!("`devices[{}].buttons.{}` maps to \"{}\", which is not a known button or \"none\"", i, from, to));
+                    },
+                    ButtonRule::Axis(options) if options.axis.parse::<AbsoluteAxisCode>().is_err() => {
+                        return Err(format!("`devices[{}].buttons.{}.axis` \"{}\" is not a known axis (expected e.g. ABS_Y)", i, from, options.axis));
+                    },
+                    _ => {}
+                }
+            }
+            for (from, rule) in &device.axes {
+                if from.parse::<AbsoluteAxisCode>().is_err() {
+                    return Err(format!("`devices[{}].axes.{}` is not a known axis (expected e.g. ABS_X)", i, from));
+                }
+                let options = rule.options();
+                if let Some(to) = &options.to
+                    && mapping::parse_axis_target(to).is_none() {
+                    return Err(format!("`devices[{}].axes.{}` maps to \"{}\", which is not a known axis or \"none\"", i, from, to));
+                }
+                if let Some(button) = &options.button {
+                    // The threshold works on the raw axis, none of these would apply.
+                    let ignored = [
+                        ("to", options.to.is_some()),
+                        ("invert", options.invert),
+                        ("deadzone", options.deadzone.is_some()),
+                        ("outer_deadzone", options.outer_deadzone.is_some()),
+                        ("curve", options.curve.is_some()),
+                        ("filter", options.filter.is_some()),
+                    ];
+                    if let Some((key, _)) = ignored.iter().find(|(_, set)| *set) {
+                        return Err(format!("`devices[{}].axes.{}` can't set both `button` and `{}`", i, from, key));
+                    }
+                    if button.parse::<KeyCode>().is_err() {
+                        return Err(format!("`devices[{}].axes.{}.button` \"{}\" is not a known button (expected e.g. BTN_TR)", i, from, button));
+                    }
+                }
+                let (press, release) = (options.press(), options.release());
+                if !(press > 0.0 && press <= 1.0) {
+                    return Err(format!("`devices[{}].axes.{}.press` must be above 0 and at most 1", i, from));
+                }
+                if !(0.0..=press).contains(&release) {
+                    return Err(format!("`devices[{}].axes.{}.release` must be between 0 and `press`", i, from));
+                }
+                check_deadzone(&format!("devices[{}].axes.{}", i, from), options.deadzone.unwrap_or(0.0), options.outer_deadzone.unwrap_or(0.0))?;
+                if let Some(curve) = &options.curve {
+                    curve.validate().map_err(|e| format!("`devices[{}].axes.{}.curve`: {}", i, from, e))?;
+                }
+                if let Some(filter) = &options.filter {
+                    filter.validate().map_err(|(key, e)| format!("`devices[{}].axes.{}.filter.{}` {}", i, from, key, e))?;
+                }
+            }
+            let mut stick_axes = HashSet::new();
+            for (j, stick) in device.sticks.iter().enumerate() {
+                for (key, axis) in [("x", &stick.x), ("y", &stick.y)] {
+                    if axis.parse::<AbsoluteAxisCode>().is_err() {
+                        return Err(format!("`devices[{}].sticks[{}].{}` \"{}\" is not a known axis (expected e.g. ABS_X)", i, j, key, axis));
+                    }
+                    if !stick_axes.insert(axis) {
+                        return Err(format!("`devices[{}].sticks[{}].{}` {} is already part of a stick", i, j, key, axis));
+                    }
+                    // Sticks only have a radial deadzone.
+                    if let Some(options) = device.axes.get(axis).map(AxisRule::options)
+                        && (options.deadzone.is_some() || options.outer_deadzone.is_some()) {
+                        return Err(format!("`devices[{}].axes.{}` is part of `devices[{}].sticks[{}]`, set its deadzones on the stick instead", i, axis, i, j));
+                    }
+                }
+                check_deadzone(&format!("devices[{}].sticks[{}]", i, j), stick.deadzone, stick.outer_deadzone)?;
+                if let Some(curve) = &stick.curve {
+                    curve.validate().map_err(|e| format!("`devices[{}].sticks[{}].curve`: {}", i, j, e))?;
+                }
+            }
+            if let Some(treadmill) = &device.treadmill {
+                treadmill.validate().map_err(|(key, e)| format!("`devices[{}].treadmill.{}` {}", i, key, e))?;
+                if device.axes.contains_key(&treadmill.axis) {
+                    return Err(format!("`devices[{}].axes.{}` is the treadmill's axis, configure it under `treadmill`", i, treadmill.axis));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// One priority per device, earlier devices outrank later ones unless configured otherwise.
+    pub fn priorities(&self) -> Vec<i32> {
+        let count = self.devices.len() as i32;
+        self.devices.iter()
+            .enumerate()
+            .map(|(i, device)| device.priority.unwrap_or(count - i as i32))
+            .collect()
+    }
+}
+
+impl Config {
+    /// One input mapping per device.
+    pub fn mappings(&self) -> Vec<Mapping> {
+        self.devices.iter().map(Mapping::new).collect()
+    }
+}
+
+impl FilterConfig {
+    /// Returns the offending key and what is wrong with it.
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        if self.median.is_some_and(|median| !(1..=MAX_MEDIAN).contains(&median)) {
+            return Err(("median", format!("must be between 1 and {}", MAX_MEDIAN)));
+        }
+        if self.smoothing.is_some_and(|smoothing| smoothing.is_nan() || smoothing < 0.0) {
+            return Err(("smoothing", "must not be negative".to_string()));
+        }
+        if self.rate_limit.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+            return Err(("rate_limit", "must be above 0".to_string()));
+        }
+        if self.min_delta.is_some_and(|delta| !(0.0..1.0).contains(&delta)) {
+            return Err(("min_delta", "must be at least 0 and below 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl TreadmillConfig {
+    /// Returns the offending key and what is wrong with it.
+    fn validate(&self) -> Result<(), (&'static str, String)> {
+        for (key, axis) in [("axis", self.axis.as_str()), ("stick", self.stick()), ("strafe_axis", self.strafe_axis())] {
+            if axis.parse::<AbsoluteAxisCode>().is_err() {
+                return Err((key, format!("\"{}\" is not a known axis (expected e.g. ABS_Y)", axis)));
+            }
+        }
+        if self.heading && self.stick() == self.strafe_axis() {
+            return Err(("strafe_axis", "must differ from `stick`".to_string()));
+        }
+        if let Some(button) = &self.sprint_button
+            && button.parse::<KeyCode>().is_err() {
+            return Err(("sprint_button", format!("\"{}\" is not a known button (expected e.g. BTN_THUMBL)", button)));
+        }
+        if let (Some(min), Some(max)) = (self.min_speed, self.max_speed)
+            && min >= max {
+            return Err(("min_speed", "must be below `max_speed`".to_string()));
+        }
+        if self.smoothing().is_nan() || self.smoothing() < 0.0 {
+            return Err(("smoothing", "must not be negative".to_string()));
+        }
+        for (key, rate) in [("ramp_up", self.ramp_up), ("ramp_down", self.ramp_down)] {
+            if rate.is_some_and(|rate| rate.is_nan() || rate <= 0.0) {
+                return Err((key, "must be above 0".to_string()));
+            }
+        }
+        if !(self.run_threshold() > 0.0 && self.run_threshold() <= 1.0) {
+            return Err(("run_threshold", "must be above 0 and at most 1".to_string()));
+        }
+        if let Some(curve) = &self.curve {
+            curve.validate().map_err(|e| ("curve", format!("is invalid: {}", e)))?;
+        }
+        Ok(())
+    }
+}
+
+fn check_deadzone(key: &str, inner: f64, outer: f64) -> Result<(), String> {
+    if !(inner >= 0.0 && outer >= 0.0 && inner + outer < 1.0) {
+        return Err(format!("`{}` deadzones must not be negative and `deadzone` plus `outer_deadzone` must stay below 1", key));
+    }
+    Ok(())
+}
+
+fn default_devices() -> Vec<DeviceConfig> {
+    DEFAULT_DEVICES.iter().map(|&name| DeviceConfig::named(name)).collect()
+}
+
+/// `$XDG_CONFIG_HOME/controller-muxer/config.toml`, falling back to `~/.config`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(base.join("controller-muxer").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn validate(text: &str) -> Result<(), String> {
+        toml::from_str::<Config>(text).expect("parses").validate()
+    }
+
+    #[test]
+    fn example_and_defaults_are_valid() {
+        assert_eq!(validate(include_str!("../config.example.toml")), Ok(()));
+        assert_eq!(Config::default().validate(), Ok(()));
+        assert_eq!(validate(""), Ok(()));
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(toml::from_str::<Config>("[[devices]]\nnmae = \"Pad\"").is_err());
+        assert!(toml::from_str::<Config>("[merge]\ndefault = \"loudest-wins\"").is_err());
+    }
+
+    #[test]
+    fn reports_the_offending_key() {
+        let cases = [
+            ("devices = []", "`devices` must declare at least one input device"),
+            ("[virtual_device]\nname = \" \"", "`virtual_device.name` must not be empty"),
+            ("[virtual_device]\nbustype = \"BUS_CAN\"", "`virtual_device.bustype` \"BUS_CAN\" is not a known bus type (expected e.g. BUS_USB)"),
+            ("[merge.axes]\nABS_Q = \"average\"", "`merge.axes.ABS_Q` is not a known axis (expected e.g. ABS_X)"),
+            ("[[devices]]\npriority = 1", "`devices[0]` needs at least one of name, exact_name, name_regex, vendor, product, uniq, phys or path"),
+            ("[[devices]]\nname = \"\"", "`devices[0].name` must not be empty"),
+            ("[[devices]]\nname = \"Pad\"\n[devices.buttons]\nBTN_SOUTH = \"BTN_NOPE\"", "`devices[0].buttons.BTN_SOUTH` maps to \"BTN_NOPE\", which is not a known button or \"none\""),
+            ("[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_Z = { button = \"BTN_TL2\", press = 0.3, release = 0.5 }", "`devices[0].axes.ABS_Z.release` must be between 0 and `press`"),
+            ("[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_X = \"ABS_Y\"\n[devices.treadmill]\naxis = \"ABS_X\"", "`devices[0].axes.ABS_X` is the treadmill's axis, configure it under `treadmill`"),
+            ("[[devices]]\nname = \"Belt\"\n[devices.treadmill]\naxis = \"ABS_X\"\nmin_speed = 10\nmax_speed = 10", "`devices[0].treadmill.min_speed` must be below `max_speed`"),
+        ];
+        for (text, error) in cases {
+            assert_eq!(validate(text).unwrap_err(), error, "{}", text);
+        }
+        let error = validate("[[devices]]\nname_regex = \"(\"").unwrap_err();
+        assert!(error.starts_with("`devices[0].name_regex` is not a valid regex"), "{}", error);
+    }
+
+    #[test]
+    fn only_one_treadmill_sets_the_heading() {
+        let belt = "[[devices]]\nname = \"Belt\"\n[devices.treadmill]\naxis = \"ABS_X\"\nheading = true\n";
+        assert_eq!(validate(belt), Ok(()));
+        assert_eq!(validate(&belt.repeat(2)).unwrap_err(), "only one `devices[].treadmill` can set `heading`");
+    }
+
+    #[test]
+    fn earlier_devices_outrank_later_ones() {
+        let config: Config = toml::from_str("[[devices]]\nname = \"A\"\n[[devices]]\nname = \"B\"\npriority = 7\n[[devices]]\nname = \"C\"").unwrap();
+        assert_eq!(config.priorities(), vec![3, 7, 1]);
+    }
+
+    #[test]
+    fn rejects_nan_deadzones() {
+        let error = validate("[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_X = { deadzone = nan }").unwrap_err();
+        assert!(error.starts_with("`devices[0].axes.ABS_X` deadzones"), "{}", error);
+        let error = validate("[[devices]]\nname = \"Pad\"\n[[devices.sticks]]\nx = \"ABS_X\"\ny = \"ABS_Y\"\nouter_deadzone = nan").unwrap_err();
+        assert!(error.starts_with("`devices[0].sticks[0]` deadzones"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_options_a_threshold_would_ignore() {
+        let error = validate("[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_Z = { button = \"BTN_TL2\", deadzone = 0.1 }").unwrap_err();
+        assert_eq!(error, "`devices[0].axes.ABS_Z` can't set both `button` and `deadzone`");
+        let error = validate("[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_Z = { button = \"BTN_TL2\", invert = true }").unwrap_err();
+        assert_eq!(error, "`devices[0].axes.ABS_Z` can't set both `button` and `invert`");
+    }
+
+    #[test]
+    fn rejects_axial_deadzones_on_stick_axes() {
+        let text = "[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_Y = { deadzone = 0.1 }\n[[devices.sticks]]\nx = \"ABS_X\"\ny = \"ABS_Y\"";
+        assert_eq!(validate(text).unwrap_err(), "`devices[0].axes.ABS_Y` is part of `devices[0].sticks[0]`, set its deadzones on the stick instead");
+        let text = "[[devices]]\nname = \"Pad\"\n[devices.axes]\nABS_Y = { curve = { type = \"power\", exponent = 2.0 } }\n[[devices.sticks]]\nx = \"ABS_X\"\ny = \"ABS_Y\"";
+        assert_eq!(validate(text), Ok(()));
+    }
+}
@@ -0,0 +1,232 @@
+use crate::config::DeviceConfig;
+use evdev::raw_stream::RawDevice;
+use regex::Regex;
+use std::collections::HashSet;
+use std::error::Error;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::{fmt, fs, io};
+
+const INPUT_DIR: &str = "/dev/input";
+const BY_ID_DIR: &str = "/dev/input/by-id";
+
+/// Every node hidden right now with its original mode, so a forced exit that skips `Drop` can still
+/// give them back.
+static HIDDEN_NODES: Mutex<Vec<(PathBuf, u32)>> = Mutex::new(Vec::new());
+
+/// Selects one physical device. Every criterion that is set has to match.
+pub struct Matcher {
+    /// Substring of the device name.
+    name: Option<String>,
+    exact_name: Option<String>,
+    name_regex: Option<Regex>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    /// Serial number or MAC address, as reported in the device's `uniq`.
+    uniq: Option<String>,
+    phys: Option<String>,
+    /// Device node, usually a stable `/dev/input/by-id/...` link, resolved when matching.
+    path: Option<PathBuf>,
+}
+
+impl Matcher {
+    pub fn new(device: &DeviceConfig) -> Result<Self, regex::Error> {
+        Ok(Self {
+            name: device.name.clone(),
+            exact_name: device.exact_name.clone(),
+            name_regex: device.name_regex.as_deref().map(Regex::new).transpose()?,
+            vendor: device.vendor,
+            product: device.product,
+            uniq: device.uniq.clone(),
+            phys: device.phys.clone(),
+            path: device.path.clone(),
+        })
+    }
+
+    /// Whether the device opened from the event node at `path` is the one we want.
+    pub fn matches(&self, path: &Path, device: &RawDevice) -> bool {
+        let name = device.name().unwrap_or_default();
+        let id = device.input_id();
+        self.name.as_ref().is_none_or(|wanted| name.contains(wanted.as_str()))
+            && self.exact_name.as_ref().is_none_or(|wanted| name == wanted)
+            && self.name_regex.as_ref().is_none_or(|regex| regex.is_match(name))
+            && self.vendor.is_none_or(|vendor| id.vendor() == vendor)
+            && self.product.is_none_or(|product| id.product() == product)
+            && self.uniq.as_ref().is_none_or(|uniq| device.unique_name().is_some_and(|found| found.eq_ignore_ascii_case(uniq)))
+            && self.phys.as_ref().is_none_or(|phys| device.physical_path() == Some(phys.as_str()))
+            && self.path.as_ref().is_none_or(|wanted| fs::canonicalize(wanted).is_ok_and(|wanted| wanted == path))
+    }
+}
+
+impl fmt::Display for Matcher {
+    /// A short description for log lines, e.g. `Xbox Wireless Controller (uniq 12:34:56:78:9a:bc)`.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(path) = &self.path {
+            parts.push(path.display().to_string());
+        }
+        if let Some(name) = self.exact_name.as_ref().or(self.name.as_ref()) {
+            parts.push(name.clone());
+        }
+        if let Some(regex) = &self.name_regex {
+            parts.push(format!("/{}/", regex));
+        }
+        match (self.vendor, self.product) {
+            (Some(vendor), Some(product)) => parts.push(format!("{:04x}:{:04x}", vendor, product)),
+            (Some(vendor), None) => parts.push(format!("vendor {:04x}", vendor)),
+            (None, Some(product)) => parts.push(format!("product {:04x}", product)),
+            (None, None) => {}
+        }
+        if let Some(uniq) = &self.uniq {
+            parts.push(format!("uniq {}", uniq));
+        }
+        if let Some(phys) = &self.phys {
+            parts.push(format!("phys {}", phys));
+        }
+        match parts.split_first() {
+            Some((first, [])) => write!(f, "{}", first),
+            Some((first, rest)) => write!(f, "{} ({})", first, rest.join(", ")),
+            None => write!(f, "any device"),
+        }
+    }
+}
+
+/// Every input device node that could be opened, plus the ones we lack the permissions for.
+pub struct Scan {
+    pub devices: Vec<(PathBuf, RawDevice)>,
+    pub denied: Vec<PathBuf>,
+}
+
+/// Opens every `/dev/input/event*` node, in numeric order.
+pub fn scan() -> io::Result<Scan> {
+    let mut paths: Vec<(u32, PathBuf)> = fs::read_dir(INPUT_DIR)?
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let number = entry.file_name().to_str()?.strip_prefix("event")?.parse().ok()?;
+            Some((number, entry.path()))
+        })
+        .collect();
+    paths.sort();
+
+    let mut scan = Scan { devices: Vec::new(), denied: Vec::new() };
+    for (_, path) in paths {
+        match RawDevice::open(&path) {
+            Ok(device) => scan.devices.push((path, device)),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => scan.denied.push(path),
+            // Nodes vanish between listing and opening when a device is unplugged.
+            Err(_) => {}
+        }
+    }
+    Ok(scan)
+}
+
+/// The stable `/dev/input/by-id` links pointing at the event node `path`.
+pub fn by_id_links(path: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = fs::read_dir(BY_ID_DIR) else {
+        return Vec::new();
+    };
+    let mut links: Vec<PathBuf> = entries
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|link| fs::canonicalize(link).is_ok_and(|target| target == path))
+        .collect();
+    links.sort();
+    links
+}
+
+/// Finds the first device `matcher` selects that isn't already `claimed` (by another source, or the
+/// muxer's own virtual device).
+pub fn find_device(matcher: &Matcher, claimed: &HashSet<PathBuf>) -> Result<(PathBuf, RawDevice), Box<dyn Error>> {
+    let scan = scan().map_err(|e| format!("Could not list {}: {}", INPUT_DIR, e))?;
+    for (path, device) in scan.devices {
+        if !claimed.contains(&path) && matcher.matches(&path, &device) {
+            match by_id_links(&path).first() {
+                Some(link) => println!("Found '{}' at path: {} ({})", matcher, path.display(), link.display()),
+                None => println!("Found '{}' at path: {}", matcher, path.display()),
+            }
+            return Ok((path, device));
+        }
+    }
+
+    let mut message = format!("Could not find controller matching '{}'.", matcher);
+    if scan.denied.is_empty() {
+        message.push_str(" Check the device is connected and the config matches it.");
+    } else {
+        let denied: Vec<String> = scan.denied.iter().map(|path| path.display().to_string()).collect();
+        message.push_str(&format!(
+            " No permission to open {} (add your user to the 'input' group or add a udev rule).",
+            denied.join(", ")
+        ));
+    }
+    Err(message.into())
+}
+
+/// A device node whose group and other permissions were taken away, restored on drop or by
+/// [`restore_hidden_nodes`].
+///
+/// Already open handles (ours) keep working, while nobody else can open the node. With uaccess ACLs
+/// the group bits act as the ACL mask, so logged in users lose access too. If the process is killed
+/// outright (SIGKILL, a crash) the node stays hidden until the device is replugged.
+pub struct HiddenNode {
+    path: PathBuf,
+}
+
+impl HiddenNode {
+    pub fn hide(path: &Path) -> io::Result<Self> {
+        let mode = fs::metadata(path)?.permissions().mode();
+        let mut hidden = HIDDEN_NODES.lock().unwrap_or_else(PoisonError::into_inner);
+        fs::set_permissions(path, fs::Permissions::from_mode(mode & !0o077))?;
+        hidden.push((path.to_path_buf(), mode));
+        Ok(Self { path: path.to_path_buf() })
+    }
+}
+
+impl Drop for HiddenNode {
+    fn drop(&mut self) {
+        let mut hidden = HIDDEN_NODES.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(index) = hidden.iter().position(|(path, _)| *path == self.path) {
+            let (path, mode) = hidden.remove(index);
+            restore(&path, mode);
+        }
+    }
+}
+
+/// Gives every hidden node its permissions back, for exits that don't unwind.
+pub fn restore_hidden_nodes() {
+    let mut hidden = HIDDEN_NODES.lock().unwrap_or_else(PoisonError::into_inner);
+    for (path, mode) in hidden.drain(..) {
+        restore(&path, mode);
+    }
+}
+
+fn restore(path: &Path, mode: u32) {
+    // Fails harmlessly when the node is gone, a replugged device gets a fresh one.
+    if path.exists()
+        && let Err(e) = fs::set_permissions(path, fs::Permissions::from_mode(mode)) {
+        eprintln!("Could not restore the permissions of {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hidden_nodes_are_restored_without_drop() {
+        let path = std::env::temp_dir().join(format!("muxer-hidden-{}", std::process::id()));
+        fs::write(&path, "").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o664)).unwrap();
+        let mode = || fs::metadata(&path).unwrap().permissions().mode() & 0o777;
+
+        let hidden = HiddenNode::hide(&path).unwrap();
+        assert_eq!(mode(), 0o600);
+        restore_hidden_nodes();
+        assert_eq!(mode(), 0o664);
+        // Already restored, dropping it leaves later changes alone.
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+        drop(hidden);
+        assert_eq!(mode(), 0o640);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,116 @@
+use evdev::raw_stream::RawDevice;
+use evdev::uinput::VirtualDevice;
+use evdev::{EventSummary, FFEffect, FFEffectCode, FFEffectData, UInputCode};
+use std::collections::HashMap;
+use std::io;
+use std::os::fd::AsFd;
+
+/// A physical device that receives the effects games play on the virtual device.
+struct Target {
+    name: String,
+    device: RawDevice,
+    /// The target's own copy of each effect, keyed by the effect ID on the virtual device.
+    effects: HashMap<i16, FFEffect>,
+}
+
+/// Forwards force feedback from the virtual device to every attached source that supports it.
+///
+/// Uploaded effects are remembered so a source that (re)connects later gets them too.
+#[derive(Default)]
+pub struct ForceFeedback {
+    effects: HashMap<i16, FFEffectData>,
+    gain: Option<u16>,
+    targets: HashMap<usize, Target>,
+}
+
+impl ForceFeedback {
+    /// Starts forwarding effects to `source`. Takes its own handle so the source's reader isn't disturbed.
+    pub fn attach(&mut self, source: usize, device: &RawDevice) -> io::Result<()> {
+        let name = device.name().unwrap_or("Unknown").to_string();
+        let device = RawDevice::from_fd(device.as_fd().try_clone_to_owned()?)?;
+        let mut target = Target { name, device, effects: HashMap::new() };
+
+        if let Some(gain) = self.gain {
+            target.device.set_ff_gain(gain)?;
+        }
+        for (&id, &data) in &self.effects {
+            target.upload(id, data);
+        }
+        println!("[{}] Forwarding force feedback.", target.name);
+        self.targets.insert(source, target);
+        Ok(())
+    }
+
+    pub fn detach(&mut self, source: usize) {
+        self.targets.remove(&source);
+    }
+
+    /// Services every pending request a game made on the virtual device: effect uploads, erases,
+    /// playback and gain changes.
+    pub fn handle_requests(&mut self, virt_device: &mut VirtualDevice) -> io::Result<()> {
+        let events: Vec<_> = virt_device.fetch_events()?.collect();
+        for event in events {
+            match event.destructure() {
+                EventSummary::UInput(event, UInputCode::UI_FF_UPLOAD, _) => {
+                    let upload = virt_device.process_ff_upload(event)?;
+                    self.upload(upload.effect_id(), upload.effect());
+                },
+                EventSummary::UInput(event, UInputCode::UI_FF_ERASE, _) => {
+                    let erase = virt_device.process_ff_erase(event)?;
+                    self.erase(erase.effect_id() as i16);
+                },
+                EventSummary::ForceFeedback(_, FFEffectCode::FF_GAIN, value) => self.set_gain(value as u16),
+                EventSummary::ForceFeedback(_, code, count) => self.play(code.0 as i16, count),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn upload(&mut self, id: i16, data: FFEffectData) {
+        self.effects.insert(id, data);
+        for target in self.targets.values_mut() {
+            target.upload(id, data);
+        }
+    }
+
+    fn erase(&mut self, id: i16) {
+        self.effects.remove(&id);
+        for target in self.targets.values_mut() {
+            // Dropping the effect removes it from the device.
+            target.effects.remove(&id);
+        }
+    }
+
+    fn play(&mut self, id: i16, count: i32) {
+        for target in self.targets.values_mut() {
+            if let Some(effect) = target.effects.get_mut(&id)
+                && let Err(e) = effect.play(count) {
+                eprintln!("[{}] Failed to play force feedback effect: {}", target.name, e);
+            }
+        }
+    }
+
+    fn set_gain(&mut self, gain: u16) {
+        self.gain = Some(gain);
+        for target in self.targets.values_mut() {
+            if let Err(e) = target.device.set_ff_gain(gain) {
+                eprintln!("[{}] Failed to set force feedback gain: {}", target.name, e);
+            }
+        }
+    }
+}
+
+impl Target {
+    fn upload(&mut self, id: i16, data: FFEffectData) {
+        let result = match self.effects.get_mut(&id) {
+            Some(effect) => effect.update(data),
+            None => self.device.upload_ff_effect(data).map(|effect| {
+                self.effects.insert(id, effect);
+            }),
+        };
+        if let Err(e) = result {
+            eprintln!("[{}] Failed to upload force feedback effect: {}", self.name, e);
+        }
+    }
+}
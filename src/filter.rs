@@ -0,0 +1,161 @@
+use crate::config::FilterConfig;
+use crate::shaping::Smoother;
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// Evens out a noisy source axis, working on deflections (-1 to 1).
+///
+/// Readings go through a median over the last few, then a low-pass and a rate limit that keep moving
+/// the output over time. Changes smaller than `min_delta` are held back so jitter doesn't flood the
+/// virtual device with events.
+pub struct Filter {
+    median: usize,
+    min_delta: f64,
+    samples: VecDeque<f64>,
+    /// Follows the median of the latest readings.
+    smoother: Smoother,
+    /// What was last passed on, `None` before the first reading.
+    sent: Option<f64>,
+}
+
+impl Filter {
+    /// Expects a validated config.
+    pub fn new(config: &FilterConfig) -> Self {
+        let median = config.median.unwrap_or(1);
+        Self {
+            median,
+            min_delta: config.min_delta.unwrap_or(0.0),
+            samples: VecDeque::with_capacity(median),
+            smoother: Smoother::new(config.smoothing.unwrap_or(0.0), config.rate_limit, config.rate_limit),
+            sent: None,
+        }
+    }
+
+    /// Takes a new reading. Returns the filtered position if it changed enough to be sent.
+    pub fn push(&mut self, position: f64, now: Instant) -> Option<f64> {
+        if self.samples.len() == self.median {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(position);
+        let target = median(&self.samples);
+        if self.sent.is_none() {
+            // Nothing to smooth from yet, start out at the device's state.
+            self.smoother.jump_to(target);
+        } else {
+            self.smoother.set_target(target);
+        }
+        self.step(now, false)
+    }
+
+    /// Moves the output on to `now`. Returns it if it changed enough to be sent.
+    pub fn update(&mut self, now: Instant) -> Option<f64> {
+        self.step(now, true)
+    }
+
+    /// `settling` is set when the output was still catching up with the readings.
+    fn step(&mut self, now: Instant, settling: bool) -> Option<f64> {
+        let output = self.smoother.update(now);
+        // Small moves that end the catching up, or reach rest or the end of the axis, still go out so
+        // the output never stays stuck just short of them.
+        let arrived = settling && self.smoother.next_update().is_none();
+        let at_limit = output == 0.0 || output.abs() == 1.0;
+        let send = match self.sent {
+            Some(sent) => sent != output && (arrived || at_limit || (output - sent).abs() >= self.min_delta),
+            None => true,
+        };
+        send.then(|| *self.sent.insert(output))
+    }
+
+    /// When the output next needs to move, `None` once it caught up with the readings.
+    pub fn next_update(&self) -> Option<Instant> {
+        self.smoother.next_update()
+    }
+
+    /// Forgets every reading, e.g. when the source disconnects.
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.smoother.reset();
+        self.sent = None;
+    }
+}
+
+fn median(samples: &VecDeque<f64>) -> f64 {
+    let mut sorted: Vec<f64> = samples.iter().copied().collect();
+    sorted.sort_by(f64::total_cmp);
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn min_delta_holds_back_jitter_but_not_rest() {
+        let mut filter = Filter::new(&FilterConfig { min_delta: Some(0.01), ..FilterConfig::default() });
+        let now = Instant::now();
+        assert_eq!(filter.push(0.5, now), Some(0.5));
+        assert_eq!(filter.push(0.505, now), None);
+        assert_eq!(filter.push(0.52, now), Some(0.52));
+        assert_eq!(filter.push(0.005, now), Some(0.005));
+        assert_eq!(filter.push(0.0, now), Some(0.0));
+        assert_eq!(filter.push(-1.0, now), Some(-1.0));
+        assert_eq!(filter.push(-0.995, now), None);
+        assert_eq!(filter.push(-1.0, now), None);
+        assert_eq!(filter.next_update(), None);
+    }
+
+    #[test]
+    fn median_drops_spikes() {
+        let mut filter = Filter::new(&FilterConfig { median: Some(3), ..FilterConfig::default() });
+        let now = Instant::now();
+        assert_eq!(filter.push(0.2, now), Some(0.2));
+        assert_eq!(filter.push(1.0, now), Some(0.6));
+        assert_eq!(filter.push(0.2, now), Some(0.2));
+        assert_eq!(filter.push(0.3, now), Some(0.3));
+        assert_eq!(filter.push(0.3, now), None);
+        assert_eq!(filter.push(-1.0, now), None);
+        assert_eq!(filter.push(0.4, now), None);
+    }
+
+    #[test]
+    fn rate_limit_keeps_moving_on_ticks() {
+        let mut filter = Filter::new(&FilterConfig { rate_limit: Some(2.0), min_delta: Some(0.3), ..FilterConfig::default() });
+        let start = Instant::now();
+        assert_eq!(filter.push(0.0, start), Some(0.0));
+        assert_eq!(filter.push(0.9, start), None);
+        assert_eq!(filter.update(start + Duration::from_millis(100)), None);
+        assert_eq!(filter.update(start + Duration::from_millis(200)), Some(0.4));
+        // The last step is small, but it finishes the move.
+        assert_eq!(filter.update(start + Duration::from_millis(400)), Some(0.8));
+        assert_eq!(filter.update(start + Duration::from_millis(450)), Some(0.9));
+        assert_eq!(filter.next_update(), None);
+    }
+
+    #[test]
+    fn smoothing_settles_on_the_reading() {
+        let mut filter = Filter::new(&FilterConfig { smoothing: Some(0.05), ..FilterConfig::default() });
+        let start = Instant::now();
+        filter.push(0.0, start);
+        filter.push(0.5, start);
+        let halfway = filter.update(start + Duration::from_millis(50)).expect("moved");
+        assert!(halfway > 0.2 && halfway < 0.5, "{}", halfway);
+        assert_eq!(filter.update(start + Duration::from_secs(1)), Some(0.5));
+        assert_eq!(filter.next_update(), None);
+    }
+
+    #[test]
+    fn reset_starts_over_from_the_next_reading() {
+        let mut filter = Filter::new(&FilterConfig { smoothing: Some(1.0), median: Some(3), ..FilterConfig::default() });
+        let now = Instant::now();
+        filter.push(0.8, now);
+        filter.reset();
+        assert_eq!(filter.next_update(), None);
+        assert_eq!(filter.push(-0.5, now), Some(-0.5));
+    }
+}
@@ -0,0 +1,67 @@
+use crate::shutdown::Shutdown;
+use nix::errno::Errno;
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use std::io;
+use std::os::fd::{AsFd, BorrowedFd};
+use std::time::Duration;
+
+const INPUT_DIR: &str = "/dev/input";
+/// How often to look again when `/dev/input` can't be watched.
+pub const FALLBACK_INTERVAL: Duration = Duration::from_secs(3);
+
+/// Notices whenever an input node appears, goes away or becomes readable (udev fixes up
+/// permissions right after creating the node).
+pub struct Hotplug {
+    inotify: Option<Inotify>,
+}
+
+impl Hotplug {
+    /// Starts watching `/dev/input`. If that fails, callers fall back to looking every few seconds.
+    pub fn start() -> Self {
+        let inotify = Inotify::init(InitFlags::IN_CLOEXEC | InitFlags::IN_NONBLOCK)
+            .and_then(|inotify| {
+                let flags = AddWatchFlags::IN_CREATE | AddWatchFlags::IN_ATTRIB | AddWatchFlags::IN_DELETE;
+                inotify.add_watch(INPUT_DIR, flags)?;
+                Ok(inotify)
+            })
+            .inspect_err(|e| eprintln!("Could not watch {} for new devices, polling instead: {}", INPUT_DIR, e))
+            .ok();
+        Self { inotify }
+    }
+
+    /// Becomes readable when `/dev/input` changed, `None` if it isn't watched.
+    pub fn fd(&self) -> Option<BorrowedFd<'_>> {
+        self.inotify.as_ref().map(Inotify::as_fd)
+    }
+
+    /// Consumes the pending notifications, returning whether any of them concerned an event node.
+    pub fn changed(&self) -> io::Result<bool> {
+        let Some(inotify) = &self.inotify else {
+            return Ok(false);
+        };
+        let is_event_node = |name: &std::ffi::OsStr| name.to_string_lossy().starts_with("event");
+        let mut changed = false;
+        loop {
+            match inotify.read_events() {
+                Ok(events) => changed |= events.iter().any(|event| event.name.as_deref().is_some_and(is_event_node)),
+                Err(Errno::EAGAIN) => return Ok(changed),
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+
+    /// Blocks until `/dev/input` changed (or a while passed, when it can't be watched).
+    /// Returns `false` instead if a shutdown was requested meanwhile.
+    pub fn wait(&self, shutdown: &Shutdown) -> io::Result<bool> {
+        loop {
+            let timeout = self.inotify.is_none().then_some(FALLBACK_INTERVAL);
+            shutdown.wait(self.fd(), timeout)?;
+            if shutdown.is_requested() {
+                return Ok(false);
+            }
+            if self.inotify.is_none() || self.changed()? {
+                return Ok(true);
+            }
+        }
+    }
+}
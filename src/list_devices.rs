@@ -0,0 +1,125 @@
+use crate::discovery::{by_id_links, scan};
+use evdev::raw_stream::RawDevice;
+use serde::Serialize;
+use std::error::Error;
+use std::path::PathBuf;
+
+#[derive(Serialize)]
+struct DeviceInfo {
+    path: PathBuf,
+    by_id: Vec<PathBuf>,
+    name: String,
+    bus: String,
+    vendor: u16,
+    product: u16,
+    version: u16,
+    uniq: Option<String>,
+    phys: Option<String>,
+    axes: Vec<AxisInfo>,
+    keys: Vec<String>,
+    force_feedback: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct AxisInfo {
+    code: String,
+    value: i32,
+    min: i32,
+    max: i32,
+    fuzz: i32,
+    flat: i32,
+    resolution: i32,
+}
+
+#[derive(Serialize)]
+struct Listing {
+    devices: Vec<DeviceInfo>,
+    /// Nodes that exist but could not be opened for lack of permissions.
+    permission_denied: Vec<PathBuf>,
+}
+
+impl DeviceInfo {
+    fn new(path: PathBuf, device: &RawDevice) -> Result<Self, Box<dyn Error>> {
+        let id = device.input_id();
+        let axes = device.get_absinfo()?
+            .map(|(code, info)| AxisInfo {
+                code: format!("{:?}", code),
+                value: info.value(),
+                min: info.minimum(),
+                max: info.maximum(),
+                fuzz: info.fuzz(),
+                flat: info.flat(),
+                resolution: info.resolution(),
+            })
+            .collect();
+        Ok(Self {
+            by_id: by_id_links(&path),
+            path,
+            name: device.name().unwrap_or("Unknown").to_string(),
+            bus: id.bus_type().to_string(),
+            vendor: id.vendor(),
+            product: id.product(),
+            version: id.version(),
+            uniq: device.unique_name().filter(|uniq| !uniq.is_empty()).map(str::to_string),
+            phys: device.physical_path().filter(|phys| !phys.is_empty()).map(str::to_string),
+            axes,
+            keys: device.supported_keys().into_iter().flat_map(|keys| keys.iter()).map(|key| format!("{:?}", key)).collect(),
+            force_feedback: device.supported_ff().into_iter().flat_map(|ff| ff.iter()).map(|ff| format!("{:?}", ff)).collect(),
+        })
+    }
+
+    fn print(&self) {
+        println!("{}: {}", self.path.display(), self.name);
+        for link in &self.by_id {
+            println!("  by-id:   {}", link.display());
+        }
+        println!("  id:      bus {}, vendor 0x{:04x}, product 0x{:04x}, version 0x{:04x}", self.bus, self.vendor, self.product, self.version);
+        if let Some(uniq) = &self.uniq {
+            println!("  uniq:    {}", uniq);
+        }
+        if let Some(phys) = &self.phys {
+            println!("  phys:    {}", phys);
+        }
+        for axis in &self.axes {
+            println!("  axis:    {} {}..{} (value {}, fuzz {}, flat {})", axis.code, axis.min, axis.max, axis.value, axis.fuzz, axis.flat);
+        }
+        if !self.keys.is_empty() {
+            println!("  keys:    {}", self.keys.join(" "));
+        }
+        if !self.force_feedback.is_empty() {
+            println!("  ff:      {}", self.force_feedback.join(" "));
+        }
+        println!();
+    }
+}
+
+/// Prints every input device, either readable for humans or as JSON for scripts.
+pub fn run(json: bool) -> Result<(), Box<dyn Error>> {
+    let scan = scan().map_err(|e| format!("Could not list input devices: {}", e))?;
+    let devices = scan.devices.into_iter()
+        .filter_map(|(path, device)| {
+            // Most likely unplugged since the scan, the others are still worth listing.
+            DeviceInfo::new(path.clone(), &device)
+                .inspect_err(|e| eprintln!("Skipping {}: {}", path.display(), e))
+                .ok()
+        })
+        .collect();
+    let listing = Listing { devices, permission_denied: scan.denied };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&listing)?);
+        return Ok(());
+    }
+
+    for device in &listing.devices {
+        device.print();
+    }
+    if listing.devices.is_empty() {
+        println!("No input devices could be opened.");
+    }
+    if !listing.permission_denied.is_empty() {
+        let denied: Vec<String> = listing.permission_denied.iter().map(|path| path.display().to_string()).collect();
+        println!("No permission to open {} (add your user to the 'input' group or add a udev rule).", denied.join(", "));
+    }
+    Ok(())
+}
@@ -1,117 +1,224 @@
+use evdev::raw_stream::RawDevice;
 use evdev::uinput::VirtualDevice;
-use evdev::{AbsInfo, AbsoluteAxisCode, AttributeSet, Device, KeyCode, UinputAbsSetup};
-use parking_lot::Mutex;
+use evdev::{AbsInfo, AbsoluteAxisCode, AttributeSet, FFEffectCode, KeyCode, UinputAbsSetup};
 use std::env;
-use std::{sync::Arc, thread, time::Duration};
+use std::ffi::CString;
+use std::collections::HashSet;
 use std::error::Error;
 
-// --- CONFIGURATION ---
-const PRIMARY_CONTROLLER_NAME: &str = "Xbox Wireless Controller";
-const SECONDARY_CONTROLLER_NAME: &str = "RealityRunner Treadmill Sensor";
-// ---------------------
-
-fn find_device_by_name(name: &str) -> Result<Device, Box<dyn Error>> {
-    for i in 0..32 {
-        let path = format!("/dev/input/event{}", i);
-        if let Ok(device) = Device::open(&path) 
-            && device.name().unwrap_or_default().contains(name) {
-            println!("Found '{}' at path: {}", name, path);
-            return Ok(device);
+mod cli;
+mod config;
+mod discovery;
+mod ff;
+mod filter;
+mod hotplug;
+mod list_devices;
+mod mapping;
+mod monitor;
+mod mux;
+mod reactor;
+mod shaping;
+mod shutdown;
+mod treadmill;
+
+use cli::Command;
+use config::{Config, DeviceConfig, Identity};
+use discovery::{find_device, Matcher};
+use hotplug::Hotplug;
+use mapping::Mapping;
+use monitor::Monitor;
+use mux::Muxer;
+use reactor::Reactor;
+use shutdown::Shutdown;
+
+/// What the virtual device declares, shared with the muxer so it knows every output range.
+struct Layout {
+    axes: Vec<(AbsoluteAxisCode, AbsInfo)>,
+    keys: AttributeSet<KeyCode>,
+    /// Force feedback effects games may upload, empty to not offer force feedback at all.
+    ff: AttributeSet<FFEffectCode>,
+}
+
+impl Layout {
+    /// The standard gamepad: two sticks, two analog triggers, a d-pad hat and the usual buttons.
+    fn gamepad() -> Self {
+        let stick_info = Self::stick_info();
+        let trigger_info = AbsInfo::new(0, 0, 1023, 0, 0, 0);
+        let hat_info = AbsInfo::new(0, -1, 1, 0, 0, 0); 
+
+        let axes = vec![
+            (AbsoluteAxisCode::ABS_X, stick_info),
+            (AbsoluteAxisCode::ABS_Y, stick_info),
+            (AbsoluteAxisCode::ABS_RX, stick_info),
+            (AbsoluteAxisCode::ABS_RY, stick_info),
+            (AbsoluteAxisCode::ABS_Z, trigger_info),
+            (AbsoluteAxisCode::ABS_RZ, trigger_info),
+            (AbsoluteAxisCode::ABS_HAT0X, hat_info),
+            (AbsoluteAxisCode::ABS_HAT0Y, hat_info),
+        ];
+
+        let mut buttons: AttributeSet<KeyCode> = AttributeSet::default();
+        buttons.insert(KeyCode::BTN_SOUTH);
+        buttons.insert(KeyCode::BTN_NORTH);
+        buttons.insert(KeyCode::BTN_EAST);
+        buttons.insert(KeyCode::BTN_WEST);
+        buttons.insert(KeyCode::BTN_SELECT);
+        buttons.insert(KeyCode::BTN_START);
+        buttons.insert(KeyCode::BTN_MODE);
+        buttons.insert(KeyCode::BTN_TL);
+        buttons.insert(KeyCode::BTN_TR);
+        buttons.insert(KeyCode::BTN_THUMBL);
+        buttons.insert(KeyCode::BTN_THUMBR);
+
+        Self { axes, keys: buttons, ff: AttributeSet::default() }
+    }
+
+    fn stick_info() -> AbsInfo {
+        AbsInfo::new(0, -32768, 32767, 16, 128, 0)
+    }
+
+    /// Declares the axis with a stick's range, unless it already exists.
+    fn add_axis(&mut self, code: AbsoluteAxisCode) {
+        if !self.axes.iter().any(|(existing, _)| *existing == code) {
+            self.axes.push((code, Self::stick_info()));
+        }
+    }
+
+    /// Adds every key and axis `device` supports. With `take_ranges` the device's AbsInfo also
+    /// replaces the range of axes that are already declared.
+    fn mirror(&mut self, device: &RawDevice, take_ranges: bool) -> Result<(), Box<dyn Error>> {
+        if let Some(keys) = device.supported_keys() {
+            for key in keys.iter() {
+                self.keys.insert(key);
+            }
+        }
+        for (code, info) in device.get_absinfo()? {
+            // The current position is not part of the capability, start the output at rest.
+            let info = shaping::at_rest(code, &info);
+            match self.axes.iter_mut().find(|(existing, _)| *existing == code) {
+                Some(axis) if take_ranges => axis.1 = info,
+                Some(_) => {}
+                None => self.axes.push((code, info)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Waits for the primary (first) device and mirrors its capabilities, then adds those of any other
+/// configured device that is already connected.
+/// Returns `false` if a shutdown was requested while waiting.
+fn mirror_capabilities(matchers: &[Matcher], layout: &mut Layout, hotplug: &Hotplug, shutdown: &Shutdown) -> Result<bool, Box<dyn Error>> {
+    let mut claimed = HashSet::new();
+    let primary = &matchers[0];
+    let (path, device) = loop {
+        match find_device(primary, &claimed) {
+            Ok(found) => break found,
+            Err(e) => println!("{} Waiting for the primary device to mirror its capabilities...", e),
+        }
+        if !hotplug.wait(shutdown)? {
+            return Ok(false);
+        }
+    };
+    layout.mirror(&device, true)?;
+    claimed.insert(path);
+
+    for matcher in &matchers[1..] {
+        match find_device(matcher, &claimed) {
+            Ok((path, device)) => {
+                layout.mirror(&device, false)?;
+                claimed.insert(path);
+            },
+            Err(_) => println!("[{}] Not connected, its extra capabilities won't be mirrored.", matcher),
         }
     }
-    Err(format!("Could not find controller named '{}'. Check device name or ensure permissions are set.", name).into())
+    Ok(true)
 }
 
-fn setup_virtual_device() -> Result<VirtualDevice, Box<dyn Error>> {
-    println!("Creating virtual 'Muxed Controller'...");
-    let stick_info = AbsInfo::new(0, -32768, 32767, 16, 128, 0);
-    let trigger_info = AbsInfo::new(0, 0, 1023, 0, 0, 0);
-    let hat_info = AbsInfo::new(0, -1, 1, 0, 0, 0); 
+fn setup_virtual_device(identity: &Identity, layout: &Layout) -> Result<VirtualDevice, Box<dyn Error>> {
+    let id = &identity.input_id;
+    println!("Creating virtual '{}' ({:04x}:{:04x}, {})...", identity.name, id.vendor(), id.product(), id.bus_type());
 
     let mut builder = VirtualDevice::builder()?
-        .name("Muxed Controller")
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_X, stick_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_Y, stick_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_RX, stick_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_RY, stick_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_Z, trigger_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_RZ, trigger_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_HAT0X, hat_info))?
-        .with_absolute_axis(&UinputAbsSetup::new(AbsoluteAxisCode::ABS_HAT0Y, hat_info))?;
-
-    let mut buttons: AttributeSet<KeyCode> = AttributeSet::default();
-    buttons.insert(KeyCode::BTN_SOUTH);
-    buttons.insert(KeyCode::BTN_NORTH);
-    buttons.insert(KeyCode::BTN_EAST);
-    buttons.insert(KeyCode::BTN_WEST);
-    buttons.insert(KeyCode::BTN_SELECT);
-    buttons.insert(KeyCode::BTN_START);
-    buttons.insert(KeyCode::BTN_MODE);
-    buttons.insert(KeyCode::BTN_TL);
-    buttons.insert(KeyCode::BTN_TR);
-    buttons.insert(KeyCode::BTN_THUMBL);
-    buttons.insert(KeyCode::BTN_THUMBR);
-    builder = builder.with_keys(&buttons)?;
+        .name(&identity.name)
+        .input_id(id.clone());
+    if let Some(phys) = &identity.phys {
+        builder = builder.with_phys(&CString::new(phys.as_str())?)?;
+    }
+    for &(code, info) in &layout.axes {
+        builder = builder.with_absolute_axis(&UinputAbsSetup::new(code, info))?;
+    }
+    builder = builder.with_keys(&layout.keys)?;
+    if layout.ff.iter().next().is_some() {
+        builder = builder
+            .with_ff(&layout.ff)?
+            .with_ff_effects_max(16);
+    }
 
     Ok(builder.build()?)
 }
 
-fn handle_controller(
-    mut source_device: Device, 
-    virt_device: Arc<Mutex<VirtualDevice>>
-) -> Result<(), Box<dyn Error>> {
-    
-    let source_name = source_device.name().unwrap_or("Unknown").to_string();
-    println!("Starting input stream for: {}", source_name);
-    source_device.grab()?;
-
-    loop {
-        for event in source_device.fetch_events()? {
-            let mut virt_dev = virt_device.lock();
-            virt_dev.emit(&[event])?;
+fn main() -> Result<(), Box<dyn Error>> {
+    let args = match cli::parse(env::args().skip(1))? {
+        Command::Run(args) => args,
+        Command::ListDevices { json } => return list_devices::run(json),
+        Command::Help => {
+            print!("{}", cli::USAGE);
+            return Ok(());
         }
-    }
-}
+    };
 
-fn main() -> Result<(), Box<dyn Error>> {
     println!("---Controller Muxer Initialization ---");
-    let args: Vec<String> = env::args().collect();
-    let primary_name = args.get(1)
-        .map(|s| s.to_owned())
-        .unwrap_or_else(|| PRIMARY_CONTROLLER_NAME.to_string());
+    let shutdown = Shutdown::install()?;
+    let mut config = Config::load(args.config_path.as_deref())?;
+    // Positional names override the configured devices in order, any beyond those are added as new sources.
+    let mut device_names = args.device_names.into_iter();
+    for (device, name) in config.devices.iter_mut().zip(device_names.by_ref()) {
+        device.match_name(name);
+    }
+    for name in device_names.chain(args.extra_devices) {
+        config.devices.push(DeviceConfig::named(name));
+    }
 
-    let secondary_name = args.get(2)
-        .map(|s| s.to_owned())
-        .unwrap_or_else(|| SECONDARY_CONTROLLER_NAME.to_string());
+    let matchers = config.devices.iter()
+        .map(Matcher::new)
+        .collect::<Result<Vec<_>, _>>()?;
 
-    let virt_device = setup_virtual_device()?;
-    let virt_device = Arc::new(Mutex::new(virt_device));
+    let hotplug = Hotplug::start();
+    let mut layout = Layout::gamepad();
+    if config.virtual_device.mirror_capabilities && !mirror_capabilities(&matchers, &mut layout, &hotplug, &shutdown)? {
+        return Ok(());
+    }
+    if config.devices.iter().any(|device| device.force_feedback) {
+        for effect in config.virtual_device.ff_effects() {
+            layout.ff.insert(effect);
+            // Games may scale the strength of whatever they play.
+            layout.ff.insert(FFEffectCode::FF_GAIN);
+        }
+    }
+    let mappings = config.mappings();
+    // Remapped buttons and axes have to exist on the virtual device even if no source has them.
+    for key in mappings.iter().flat_map(Mapping::targets) {
+        layout.keys.insert(key);
+    }
+    for axis in mappings.iter().flat_map(Mapping::axis_targets) {
+        layout.add_axis(axis);
+    }
+    let identity = config.virtual_device.identity();
+    let virt_device = setup_virtual_device(&identity, &layout)?;
+    let muxer = Muxer::new(&layout.axes, |axis| config.merge.policy_for(axis), config.priorities(), mappings);
 
-    println!("Using {primary_name} and {secondary_name} to mux. Start the target game/application and select 'Muxed Controller'.");
+    let names: Vec<String> = matchers.iter().map(Matcher::to_string).collect();
+    println!("Using {} to mux. Start the target game/application and select '{}'.", names.join(", "), identity.name);
     println!("Press Ctrl+C to stop.");
-    
-    let connection_loop = |controller_name: String, virt_device: Arc<Mutex<VirtualDevice>>| {
-        thread::spawn(move || {
-            loop {
-                match find_device_by_name(&controller_name) {
-                    Ok(dev) => {
-                        if let Err(e) = handle_controller(dev, Arc::clone(&virt_device)) {
-                            eprintln!("[{}] Handler exited (reconnecting in 3s): {}", controller_name, e);
-                        }
-                    },
-                    Err(_) => {
-                        println!("[{}] Device not yet found. Searching in 3s...", controller_name);
-                    }
-                }
-                thread::sleep(Duration::from_secs(3)); 
-            }
-        })
-    };
 
-    let _primary_handle = connection_loop(primary_name, Arc::clone(&virt_device));
-    let _secondary_handle = connection_loop(secondary_name, Arc::clone(&virt_device));
-
-    _primary_handle.join().unwrap();
-    _secondary_handle.join().unwrap();
+    let monitor = args.monitor.then(|| Monitor::new(names)).transpose()?;
+    let forward_ff = layout.ff.iter().next().is_some();
+    let sources = config.devices.into_iter().zip(matchers).collect();
+    let reactor = Reactor::new(muxer, virt_device, forward_ff, hotplug, shutdown, sources, monitor)?;
+    let virt_device = reactor.run()?;
+    // Closing uinput destroys the virtual device.
+    drop(virt_device);
+    println!("Virtual device removed.");
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,303 @@
+use crate::config::{ButtonRule, DeviceConfig};
+use crate::filter::Filter;
+use crate::shaping::{self, Curve, Deadzone};
+use crate::treadmill::Treadmill;
+use evdev::{AbsInfo, AbsoluteAxisCode, KeyCode};
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// How one source's inputs are translated before they are merged with the other sources, including
+/// the state of stages that change over time.
+pub struct Mapping {
+    /// Button remaps, `None` drops the button.
+    buttons: HashMap<KeyCode, Option<ButtonTarget>>,
+    /// Axis routes, `None` drops the axis.
+    axes: HashMap<AbsoluteAxisCode, Option<AxisTarget>>,
+    /// Noise filters, keyed by source axis.
+    filters: HashMap<AbsoluteAxisCode, Filter>,
+    /// Axial deadzones, keyed by source axis.
+    deadzones: HashMap<AbsoluteAxisCode, Deadzone>,
+    /// Response curves, keyed by source axis.
+    curves: HashMap<AbsoluteAxisCode, Curve>,
+    /// Axis pairs sharing a radial deadzone and curve.
+    sticks: Vec<Stick>,
+    treadmill: Option<Treadmill>,
+}
+
+/// Two source axes forming one stick.
+struct Stick {
+    x: AbsoluteAxisCode,
+    y: AbsoluteAxisCode,
+    deadzone: Deadzone,
+    curve: Option<Curve>,
+}
+
+/// What a source button does.
+#[derive(Clone, Copy)]
+pub enum ButtonTarget {
+    Key(KeyCode),
+    /// Holds the virtual axis at `value` while pressed, back at rest when released.
+    Axis { axis: AbsoluteAxisCode, value: i32 },
+}
+
+/// What a source axis does.
+#[derive(Clone, Copy)]
+pub enum AxisTarget {
+    Axis(AxisRoute),
+    Button(Threshold),
+    /// The belt speed of the source's treadmill.
+    Treadmill,
+}
+
+/// Which virtual axis a source axis drives, and how.
+#[derive(Clone, Copy)]
+pub struct AxisRoute {
+    pub to: AbsoluteAxisCode,
+    pub invert: bool,
+}
+
+/// Presses `key` while the axis is pushed past `press`, and only releases it again once it drops
+/// below `release`, so a value hovering around the threshold doesn't chatter.
+#[derive(Clone, Copy)]
+pub struct Threshold {
+    pub key: KeyCode,
+    press: f64,
+    release: f64,
+}
+
+impl Threshold {
+    /// Whether the button should be down with the axis at `value`. Both thresholds are fractions
+    /// of the way from rest to the end of `range` the value is on.
+    pub fn pressed(&self, value: i32, range: &AbsInfo, held: bool) -> bool {
+        shaping::deflection(value, range).abs() >= if held { self.release } else { self.press }
+    }
+}
+
+impl Mapping {
+    /// Expects a validated config, names that don't parse are skipped.
+    pub fn new(device: &DeviceConfig) -> Self {
+        let buttons = device.buttons.iter()
+            .filter_map(|(from, rule)| {
+                let target = match rule {
+                    ButtonRule::Target(to) => parse_target(to)?.map(ButtonTarget::Key),
+                    ButtonRule::Axis(options) => Some(ButtonTarget::Axis { axis: options.axis.parse().ok()?, value: options.value }),
+                };
+                Some((from.parse().ok()?, target))
+            })
+            .collect();
+        let axes = device.axes.iter()
+            .filter_map(|(from, rule)| {
+                let options = rule.options();
+                let from: AbsoluteAxisCode = from.parse().ok()?;
+                if let Some(key) = &options.button {
+                    let threshold = Threshold {
+                        key: key.parse().ok()?,
+                        press: options.press(),
+                        release: options.release(),
+                    };
+                    return Some((from, Some(AxisTarget::Button(threshold))));
+                }
+                let to = match &options.to {
+                    Some(to) => parse_axis_target(to)?,
+                    None => Some(from),
+                };
+                let route = to.map(|to| AxisTarget::Axis(AxisRoute { to, invert: options.invert }));
+                Some((from, route))
+            })
+            .collect();
+        let filters = device.axes.iter()
+            .filter_map(|(from, rule)| Some((from.parse().ok()?, Filter::new(&rule.options().filter?))))
+            .collect();
+        let deadzones = device.axes.iter()
+            .filter_map(|(from, rule)| {
+                let options = rule.options();
+                if options.deadzone.is_none() && options.outer_deadzone.is_none() {
+                    return None;
+                }
+                let deadzone = Deadzone::new(options.deadzone.unwrap_or(0.0), options.outer_deadzone.unwrap_or(0.0));
+                Some((from.parse().ok()?, deadzone))
+            })
+            .collect();
+        let curves = device.axes.iter()
+            .filter_map(|(from, rule)| Some((from.parse().ok()?, rule.options().curve?)))
+            .collect();
+        let sticks = device.sticks.iter()
+            .filter_map(|stick| Some(Stick {
+                x: stick.x.parse().ok()?,
+                y: stick.y.parse().ok()?,
+                deadzone: Deadzone::new(stick.deadzone, stick.outer_deadzone),
+                curve: stick.curve.clone(),
+            }))
+            .collect();
+        let treadmill = device.treadmill.as_ref().and_then(Treadmill::new);
+        Self { buttons, axes, filters, deadzones, curves, sticks, treadmill }
+    }
+
+    /// What the source button `key` does, if anything.
+    pub fn button(&self, key: KeyCode) -> Option<ButtonTarget> {
+        self.buttons.get(&key).copied().unwrap_or(Some(ButtonTarget::Key(key)))
+    }
+
+    /// What the source axis `code` does, if anything.
+    pub fn axis(&self, code: AbsoluteAxisCode) -> Option<AxisTarget> {
+        if self.treadmill.as_ref().is_some_and(|treadmill| treadmill.axis() == code) {
+            return Some(AxisTarget::Treadmill);
+        }
+        self.axes.get(&code).copied().unwrap_or(Some(AxisTarget::Axis(AxisRoute { to: code, invert: false })))
+    }
+
+    /// Passes a new deflection of the source axis `from` through its filter. Returns what to use
+    /// instead, `None` if nothing changed enough to be sent.
+    pub fn filter(&mut self, from: AbsoluteAxisCode, position: f64, now: Instant) -> Option<f64> {
+        match self.filters.get_mut(&from) {
+            Some(filter) => filter.push(position, now),
+            None => Some(position),
+        }
+    }
+
+    /// Moves every filter that is due on to `now`, returning the source axes whose output changed.
+    pub fn update_filters(&mut self, now: Instant) -> Vec<(AbsoluteAxisCode, f64)> {
+        self.filters.iter_mut()
+            .filter(|(_, filter)| filter.next_update().is_some_and(|due| due <= now))
+            .filter_map(|(&code, filter)| Some((code, filter.update(now)?)))
+            .collect()
+    }
+
+    /// When a filter or the treadmill next needs to move, `None` while nothing is changing.
+    pub fn next_update(&self) -> Option<Instant> {
+        self.filters.values()
+            .filter_map(Filter::next_update)
+            .chain(self.treadmill.as_ref().and_then(Treadmill::next_update))
+            .min()
+    }
+
+    /// Forgets the state of filters and the treadmill, e.g. when the source disconnects.
+    pub fn reset(&mut self) {
+        self.filters.values_mut().for_each(Filter::reset);
+        if let Some(treadmill) = &mut self.treadmill {
+            treadmill.reset();
+        }
+    }
+
+    /// Runs the deadzones and curves for the source axis `from`, which just moved. `positions` holds
+    /// the deflection of every source axis. Returns the shaped deflection of each axis that changes
+    /// with it: `from` itself, or both halves of its stick.
+    pub fn shape(&self, from: AbsoluteAxisCode, positions: &HashMap<AbsoluteAxisCode, f64>) -> Vec<(AbsoluteAxisCode, f64)> {
+        let position = |code| positions.get(&code).copied();
+        let Some(stick) = self.sticks.iter().find(|stick| stick.x == from || stick.y == from) else {
+            let mut position = position(from).unwrap_or(0.0);
+            if let Some(deadzone) = self.deadzones.get(&from) {
+                position = deadzone.apply(position);
+            }
+            return vec![(from, self.curve(from, position))];
+        };
+
+        let (x, y) = (position(stick.x), position(stick.y));
+        let (mut out_x, mut out_y) = stick.deadzone.apply_radial(x.unwrap_or(0.0), y.unwrap_or(0.0));
+        if let Some(curve) = &stick.curve {
+            (out_x, out_y) = curve.apply_radial(out_x, out_y);
+        }
+        // An axis that never reported stays unreported.
+        [(stick.x, x, out_x), (stick.y, y, out_y)].into_iter()
+            .filter(|(_, reported, _)| reported.is_some())
+            .map(|(code, _, out)| (code, self.curve(code, out)))
+            .collect()
+    }
+
+    fn curve(&self, code: AbsoluteAxisCode, position: f64) -> f64 {
+        self.curves.get(&code).map_or(position, |curve| curve.apply(position))
+    }
+
+    pub fn treadmill(&self) -> Option<&Treadmill> {
+        self.treadmill.as_ref()
+    }
+
+    pub fn treadmill_mut(&mut self) -> Option<&mut Treadmill> {
+        self.treadmill.as_mut()
+    }
+
+    /// Source buttons that drive an axis, with the axis and the value they hold it at.
+    pub fn button_axes(&self) -> impl Iterator<Item = (KeyCode, AbsoluteAxisCode, i32)> + '_ {
+        self.buttons.iter().filter_map(|(&key, target)| match target {
+            Some(ButtonTarget::Axis { axis, value }) => Some((key, *axis, *value)),
+            _ => None,
+        })
+    }
+
+    /// Whether the virtual button `key` is pressed by one of the source's axes.
+    pub fn is_axis_button(&self, key: KeyCode) -> bool {
+        self.axes.values().any(|target| matches!(target, Some(AxisTarget::Button(threshold)) if threshold.key == key))
+    }
+
+    /// Buttons this mapping may press that the source itself might not report under that name.
+    pub fn targets(&self) -> impl Iterator<Item = KeyCode> + '_ {
+        let buttons = self.buttons.values().filter_map(|target| match target {
+            Some(ButtonTarget::Key(key)) => Some(*key),
+            _ => None,
+        });
+        let thresholds = self.axes.values().filter_map(|target| match target {
+            Some(AxisTarget::Button(threshold)) => Some(threshold.key),
+            _ => None,
+        });
+        let sprint = self.treadmill.as_ref().and_then(Treadmill::sprint_button);
+        buttons.chain(thresholds).chain(sprint)
+    }
+
+    /// Virtual axes this mapping routes source axes or buttons to.
+    pub fn axis_targets(&self) -> impl Iterator<Item = AbsoluteAxisCode> + '_ {
+        let axes = self.axes.values().filter_map(|target| match target {
+            Some(AxisTarget::Axis(route)) => Some(route.to),
+            _ => None,
+        });
+        let treadmill = self.treadmill.iter().flat_map(|treadmill| [Some(treadmill.stick()), treadmill.heading()]).flatten();
+        axes.chain(self.button_axes().map(|(_, axis, _)| axis)).chain(treadmill)
+    }
+}
+
+/// Parses the right-hand side of a remap rule: a button name or `"none"`.
+pub fn parse_target(name: &str) -> Option<Option<KeyCode>> {
+    if name.eq_ignore_ascii_case("none") {
+        Some(None)
+    } else {
+        name.parse().ok().map(Some)
+    }
+}
+
+/// Parses the virtual side of an axis route: an axis name or `"none"`.
+pub fn parse_axis_target(name: &str) -> Option<Option<AbsoluteAxisCode>> {
+    if name.eq_ignore_ascii_case("none") {
+        Some(None)
+    } else {
+        name.parse().ok().map(Some)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapping(text: &str) -> Mapping {
+        Mapping::new(&toml::from_str::<DeviceConfig>(text).expect("parses"))
+    }
+
+    #[test]
+    fn shapes_axes_on_their_own_or_as_a_stick() {
+        let mapping = mapping("name = \"Pad\"\n[axes]\nABS_Z = { deadzone = 0.5 }\n[[sticks]]\nx = \"ABS_X\"\ny = \"ABS_Y\"\ndeadzone = 0.5");
+        let positions = HashMap::from([
+            (AbsoluteAxisCode::ABS_X, 0.4),
+            (AbsoluteAxisCode::ABS_Y, 0.4),
+            (AbsoluteAxisCode::ABS_Z, 0.4),
+        ]);
+        assert_eq!(mapping.shape(AbsoluteAxisCode::ABS_Z, &positions), vec![(AbsoluteAxisCode::ABS_Z, 0.0)]);
+        let shaped = mapping.shape(AbsoluteAxisCode::ABS_X, &positions);
+        assert_eq!(shaped.len(), 2);
+        assert!(shaped.iter().all(|&(_, position)| position > 0.0));
+    }
+
+    #[test]
+    fn unreported_stick_half_stays_unreported() {
+        let mapping = mapping("name = \"Pad\"\n[[sticks]]\nx = \"ABS_X\"\ny = \"ABS_Y\"");
+        let positions = HashMap::from([(AbsoluteAxisCode::ABS_X, -0.5)]);
+        assert_eq!(mapping.shape(AbsoluteAxisCode::ABS_X, &positions), vec![(AbsoluteAxisCode::ABS_X, -0.5)]);
+    }
+}
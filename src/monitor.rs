@@ -0,0 +1,170 @@
+use crate::mux::Snapshot;
+use evdev::AbsoluteAxisCode;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::unistd::{dup2, pipe2};
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::time::{Duration, Instant};
+
+const REFRESH_INTERVAL: Duration = Duration::from_millis(100);
+const COLUMN_WIDTH: usize = 20;
+/// How many of the latest log lines are shown below the table.
+const LOG_LINES: usize = 10;
+
+/// Redraws a table of what every source sends next to the merged output, in place.
+///
+/// Everything else printed while it runs would scroll the table apart, so stdout and stderr are
+/// redirected into a pipe and their lines shown in a log area below the table instead.
+pub struct Monitor {
+    labels: Vec<String>,
+    next_draw: Instant,
+    /// The terminal stdout pointed to before, the table is drawn there.
+    terminal: File,
+    stderr: OwnedFd,
+    /// Read end of the pipe stdout and stderr now write to.
+    output: File,
+    /// Output that doesn't end in a newline yet.
+    partial: Vec<u8>,
+    log: VecDeque<String>,
+    /// Lines in `log` that were never drawn.
+    undrawn: usize,
+}
+
+impl Monitor {
+    pub fn new(labels: Vec<String>) -> io::Result<Self> {
+        let terminal = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+        let stderr = io::stderr().as_fd().try_clone_to_owned()?;
+        let (output, input) = pipe2(OFlag::O_CLOEXEC)?;
+        // Only the read end: a full pipe should hold up whoever prints, not lose their output.
+        let flags = OFlag::from_bits_retain(fcntl(output.as_raw_fd(), FcntlArg::F_GETFL)?);
+        fcntl(output.as_raw_fd(), FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+        io::stdout().flush()?;
+        dup2(input.as_raw_fd(), io::stdout().as_raw_fd())?;
+        dup2(input.as_raw_fd(), io::stderr().as_raw_fd())?;
+        Ok(Self {
+            labels,
+            next_draw: Instant::now(),
+            terminal,
+            stderr,
+            output: File::from(output),
+            partial: Vec::new(),
+            log: VecDeque::with_capacity(LOG_LINES),
+            undrawn: 0,
+        })
+    }
+
+    /// When the next frame is due.
+    pub fn next_draw(&self) -> Instant {
+        self.next_draw
+    }
+
+    pub fn draw(&mut self, snapshot: &Snapshot) {
+        let frame = render(snapshot, &self.labels, &self.log);
+        // Home the cursor and clear what the previous frame left behind instead of clearing
+        // the whole screen, which flickers.
+        let _ = write!(self.terminal, "\x1b[H{}\x1b[J", frame);
+        let _ = self.terminal.flush();
+        self.undrawn = 0;
+        self.next_draw = Instant::now() + REFRESH_INTERVAL;
+    }
+
+    /// Collects whatever was printed since the last call into the log area.
+    pub fn read_log(&mut self) {
+        let mut buffer = [0; 4096];
+        while let Ok(count @ 1..) = self.output.read(&mut buffer) {
+            self.partial.extend_from_slice(&buffer[..count]);
+        }
+        while let Some(end) = self.partial.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = self.partial.drain(..=end).collect();
+            if self.log.len() == LOG_LINES {
+                self.log.pop_front();
+            }
+            self.log.push_back(String::from_utf8_lossy(&line[..end]).into_owned());
+            self.undrawn = (self.undrawn + 1).min(LOG_LINES);
+        }
+    }
+}
+
+/// Readable when something was printed, see [`Monitor::read_log`].
+impl AsFd for Monitor {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.output.as_fd()
+    }
+}
+
+/// Gives stdout and stderr back and prints what was logged after the last frame, e.g. while
+/// shutting down or a panic message.
+impl Drop for Monitor {
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        self.read_log();
+        let _ = dup2(self.terminal.as_raw_fd(), io::stdout().as_raw_fd());
+        let _ = dup2(self.stderr.as_raw_fd(), io::stderr().as_raw_fd());
+        for line in self.log.iter().skip(self.log.len() - self.undrawn) {
+            let _ = writeln!(self.terminal, "{}", line);
+        }
+        if !self.partial.is_empty() {
+            let _ = writeln!(self.terminal, "{}", String::from_utf8_lossy(&self.partial));
+        }
+    }
+}
+
+fn column(text: &str) -> String {
+    let text: String = text.chars().take(COLUMN_WIDTH - 1).collect();
+    format!("{:>width$}", text, width = COLUMN_WIDTH)
+}
+
+fn render(snapshot: &Snapshot, labels: &[String], log: &VecDeque<String>) -> String {
+    let mut out = String::new();
+    let mut line = |text: String| {
+        let _ = writeln!(out, "{}\x1b[K", text);
+    };
+
+    line("Controller Muxer monitor (Ctrl+C to stop)".to_string());
+    line(String::new());
+
+    let mut header = format!("{:<12}", "");
+    for (label, connected) in labels.iter().zip(&snapshot.connected) {
+        header.push_str(&column(if *connected { label } else { "(not connected)" }));
+    }
+    header.push_str(&column("OUTPUT"));
+    line(header);
+
+    // Every axis a source sends or the virtual device has, each source's column showing its own
+    // value before any mapping.
+    let mut codes: Vec<AbsoluteAxisCode> = snapshot.source_axes.iter()
+        .flatten()
+        .chain(&snapshot.output_axes)
+        .map(|&(code, _)| code)
+        .collect();
+    codes.sort_by_key(|code| code.0);
+    codes.dedup();
+    let value_of = |axes: &[(AbsoluteAxisCode, i32)], code: AbsoluteAxisCode| {
+        axes.iter().find(|(axis, _)| *axis == code).map(|(_, value)| value.to_string())
+    };
+    for code in codes {
+        let mut row = format!("{:<12}", format!("{:?}", code));
+        for axes in &snapshot.source_axes {
+            row.push_str(&column(&value_of(axes, code).unwrap_or("-".to_string())));
+        }
+        row.push_str(&column(&value_of(&snapshot.output_axes, code).unwrap_or_default()));
+        line(row);
+    }
+
+    line(String::new());
+    for (label, keys) in labels.iter().zip(&snapshot.held_keys) {
+        let keys: Vec<String> = keys.iter().map(|key| format!("{:?}", key)).collect();
+        line(format!("{:<30} {}", label, keys.join(" ")));
+    }
+    let keys: Vec<String> = snapshot.output_keys.iter().map(|key| format!("{:?}", key)).collect();
+    line(format!("{:<30} {}", "OUTPUT", keys.join(" ")));
+
+    line(String::new());
+    for entry in log {
+        line(entry.clone());
+    }
+    out
+}
@@ -0,0 +1,836 @@
+use crate::mapping::{AxisTarget, ButtonTarget, Mapping};
+use crate::shaping;
+use evdev::{AbsInfo, AbsoluteAxisCode, AttributeSetRef, EventType, InputEvent, KeyCode};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+
+/// How the output value of an axis is picked when several sources drive it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AxisPolicy {
+    /// The source that moved the axis most recently decides its value.
+    #[default]
+    LastWriterWins,
+    /// The source pushed furthest from the rest position decides.
+    LargestMagnitudeWins,
+    /// Deflections of all sources are added up and clamped to the axis range.
+    SumAndClamp,
+    /// The highest priority source that is off its rest position decides.
+    PriorityOrdered,
+    /// The mean of every source that reports the axis.
+    Average,
+}
+
+/// How far (0 to 1) from rest a source may be and still count as at rest for `priority-ordered`.
+const REST_BAND: f64 = 0.05;
+
+struct Axis {
+    info: AbsInfo,
+    policy: AxisPolicy,
+    /// Current value per source index, `None` if that source never reported the axis.
+    values: Vec<Option<i32>>,
+    /// When each source last moved the axis, counted in `writes` (0 if never).
+    written: Vec<u64>,
+    writes: u64,
+    output: i32,
+}
+
+impl Axis {
+    /// Where the axis sits when nothing touches it.
+    fn rest(&self) -> i32 {
+        self.info.value()
+    }
+
+    /// Whether `value` is close enough to rest to count as let go: within the axis's `flat` or
+    /// `REST_BAND` of its travel, whichever is wider, so jitter and rescaling offsets don't count.
+    fn near_rest(&self, value: i32) -> bool {
+        let (rest, min, max) = (self.rest() as i64, self.info.minimum() as i64, self.info.maximum() as i64);
+        let travel = (max - rest).max(rest - min) as f64;
+        let band = (self.info.flat() as f64).max(REST_BAND * travel);
+        ((value as i64 - rest).abs() as f64) <= band
+    }
+
+    /// The output according to the axis's policy, leaving out the source `skip` if given.
+    fn merged(&self, priorities: &[i32], skip: Option<usize>) -> i32 {
+        let rest = self.rest();
+        let reported = || self.values.iter()
+            .enumerate()
+            .filter(|(i, _)| Some(*i) != skip)
+            .filter_map(|(i, v)| v.map(|v| (i, v)));
+
+        let value = match self.policy {
+            AxisPolicy::LastWriterWins => reported()
+                .max_by_key(|(i, _)| self.written[*i])
+                .map_or(rest, |(_, v)| v) as i64,
+            AxisPolicy::LargestMagnitudeWins => reported()
+                .map(|(_, v)| v)
+                .max_by_key(|v| (*v as i64 - rest as i64).abs())
+                .unwrap_or(rest) as i64,
+            AxisPolicy::SumAndClamp => rest as i64 + reported()
+                .map(|(_, v)| v as i64 - rest as i64)
+                .sum::<i64>(),
+            AxisPolicy::PriorityOrdered => reported()
+                .filter(|(_, v)| !self.near_rest(*v))
+                .max_by_key(|(i, _)| (priorities[*i], std::cmp::Reverse(*i)))
+                .map_or(rest, |(_, v)| v) as i64,
+            AxisPolicy::Average => {
+                let (sum, count) = reported().fold((0i64, 0i64), |(sum, count), (_, v)| (sum + v as i64, count + 1));
+                if count == 0 { rest as i64 } else { sum / count }
+            }
+        };
+        value.clamp(self.info.minimum() as i64, self.info.maximum() as i64) as i32
+    }
+}
+
+/// How far (0 to 1) the steering stick has to be pushed before it sets the treadmill's heading.
+const HEADING_THRESHOLD: f64 = 0.25;
+
+/// A treadmill whose speed is combined with where the other sources point a stick.
+#[derive(Clone, Copy)]
+struct Heading {
+    /// The treadmill's source.
+    source: usize,
+    /// The stick's sideways and forward axes.
+    x: AbsoluteAxisCode,
+    y: AbsoluteAxisCode,
+}
+
+/// A copy of the muxer's state for display: what every source sends and what the virtual device outputs.
+pub struct Snapshot {
+    pub connected: Vec<bool>,
+    /// Per source, the last value of each of its axes as it sent it, before any mapping. Sorted by
+    /// axis code.
+    pub source_axes: Vec<Vec<(AbsoluteAxisCode, i32)>>,
+    /// The virtual device's axes, sorted by axis code.
+    pub output_axes: Vec<(AbsoluteAxisCode, i32)>,
+    /// Sorted buttons held per source, before any mapping.
+    pub held_keys: Vec<Vec<KeyCode>>,
+    pub output_keys: Vec<KeyCode>,
+}
+
+/// Merges the state of every source into one output. It only produces the events for the virtual
+/// device, writing them is up to the caller.
+pub struct Muxer {
+    axes: HashMap<AbsoluteAxisCode, Axis>,
+    /// Keys held down per source index, the output holds a key while any source does.
+    held_keys: Vec<HashSet<KeyCode>>,
+    /// Axis ranges each source reports, used to rescale its values into the virtual device's ranges.
+    source_ranges: Vec<HashMap<AbsoluteAxisCode, AbsInfo>>,
+    /// Deflection (-1 to 1) of each source axis after routing, before deadzones and curves. Sticks
+    /// need both halves.
+    positions: Vec<HashMap<AbsoluteAxisCode, f64>>,
+    connected: Vec<bool>,
+    priorities: Vec<i32>,
+    mappings: Vec<Mapping>,
+    heading: Option<Heading>,
+    /// What each source last sent, before any mapping, only kept for [`Muxer::snapshot`].
+    raw_axes: Vec<HashMap<AbsoluteAxisCode, i32>>,
+    raw_keys: Vec<HashSet<KeyCode>>,
+    /// Output events of the frame being applied, to be written out together with a single SYN_REPORT.
+    pending: Vec<InputEvent>,
+}
+
+impl Muxer {
+    /// `axes` lists the virtual device's axes. `priorities` (higher wins) and `mappings` hold one
+    /// entry per source.
+    pub fn new(
+        axes: &[(AbsoluteAxisCode, AbsInfo)],
+        policies: impl Fn(AbsoluteAxisCode) -> AxisPolicy,
+        priorities: Vec<i32>,
+        mappings: Vec<Mapping>,
+    ) -> Self {
+        let axes = axes.iter()
+            .map(|&(code, info)| {
+                let info = shaping::at_rest(code, &info);
+                let axis = Axis {
+                    info,
+                    policy: policies(code),
+                    values: vec![None; priorities.len()],
+                    written: vec![0; priorities.len()],
+                    writes: 0,
+                    output: info.value(),
+                };
+                (code, axis)
+            })
+            .collect();
+        let held_keys = vec![HashSet::new(); priorities.len()];
+        let source_ranges = vec![HashMap::new(); priorities.len()];
+        let positions = vec![HashMap::new(); priorities.len()];
+        let heading = mappings.iter()
+            .enumerate()
+            .find_map(|(source, mapping)| {
+                let treadmill = mapping.treadmill()?;
+                Some(Heading { source, x: treadmill.heading()?, y: treadmill.stick() })
+            });
+        let connected = vec![false; priorities.len()];
+        let raw_axes = vec![HashMap::new(); priorities.len()];
+        let raw_keys = vec![HashSet::new(); priorities.len()];
+        Self {
+            axes,
+            held_keys,
+            source_ranges,
+            positions,
+            connected,
+            priorities,
+            mappings,
+            heading,
+            raw_axes,
+            raw_keys,
+            pending: Vec::new(),
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let sorted_axes = |mut axes: Vec<(AbsoluteAxisCode, i32)>| {
+            axes.sort_by_key(|(code, _)| code.0);
+            axes
+        };
+        let source_axes = self.raw_axes.iter()
+            .map(|axes| sorted_axes(axes.iter().map(|(&code, &value)| (code, value)).collect()))
+            .collect();
+        let output_axes = sorted_axes(self.axes.iter().map(|(&code, axis)| (code, axis.output)).collect());
+
+        let held_keys = self.raw_keys.iter()
+            .map(|keys| {
+                let mut keys: Vec<KeyCode> = keys.iter().copied().collect();
+                keys.sort();
+                keys
+            })
+            .collect();
+        let mut output_keys: Vec<KeyCode> = self.held_keys.iter().flatten().copied().collect();
+        output_keys.sort();
+        output_keys.dedup();
+
+        Snapshot { connected: self.connected.clone(), source_axes, output_axes, held_keys, output_keys }
+    }
+
+    /// Applies one report from `source` (everything before its SYN_REPORT). Returns the resulting
+    /// changes, to be emitted on the virtual device as one report of its own.
+    pub fn handle_frame(&mut self, source: usize, events: &[InputEvent]) -> Vec<InputEvent> {
+        for &event in events {
+            match event.event_type() {
+                EventType::ABSOLUTE => self.handle_axis(source, event),
+                EventType::KEY => self.handle_button(source, KeyCode(event.code()), event.value()),
+                EventType::SYNCHRONIZATION => {}
+                _ => self.pending.push(event),
+            }
+        }
+        self.flush()
+    }
+
+    /// Replaces the state of `source` with a full snapshot, e.g. when it attaches or after SYN_DROPPED.
+    /// `axes` carries the source's `AbsInfo`, whose ranges are kept for rescaling its later events.
+    /// Returns the output events like [`Muxer::handle_frame`].
+    pub fn resync(&mut self, source: usize, keys: &AttributeSetRef<KeyCode>, axes: &[(AbsoluteAxisCode, AbsInfo)]) -> Vec<InputEvent> {
+        self.source_ranges[source] = axes.iter().map(|(code, info)| (*code, shaping::at_rest(*code, info))).collect();
+        self.connected[source] = true;
+        self.raw_axes[source].clear();
+        self.raw_keys[source] = keys.iter().collect();
+
+        let mapping = &self.mappings[source];
+        let down: HashSet<KeyCode> = keys.iter()
+            .filter_map(|key| match mapping.button(key) {
+                Some(ButtonTarget::Key(key)) => Some(key),
+                _ => None,
+            })
+            .collect();
+        // Buttons pressed by an axis are brought up to date with the axes below.
+        let released: Vec<KeyCode> = self.held_keys[source].difference(&down)
+            .filter(|key| !mapping.is_axis_button(**key))
+            .copied()
+            .collect();
+        let button_axes: Vec<(AbsoluteAxisCode, Option<i32>)> = mapping.button_axes()
+            .map(|(key, axis, value)| (axis, keys.contains(key).then_some(value)))
+            .collect();
+        for key in released {
+            self.handle_key(source, key, 0);
+        }
+        for key in down {
+            self.handle_key(source, key, 1);
+        }
+        for (axis, value) in button_axes {
+            self.hold_axis(source, axis, value);
+        }
+        for &(code, info) in axes {
+            self.handle_axis(source, InputEvent::new(EventType::ABSOLUTE.0, code.0, info.value()));
+        }
+        self.flush()
+    }
+
+    fn flush(&mut self) -> Vec<InputEvent> {
+        std::mem::take(&mut self.pending)
+    }
+
+    fn handle_button(&mut self, source: usize, key: KeyCode, value: i32) {
+        if value == 1 {
+            self.raw_keys[source].insert(key);
+        } else if value == 0 {
+            self.raw_keys[source].remove(&key);
+        }
+        match self.mappings[source].button(key) {
+            Some(ButtonTarget::Key(key)) => self.handle_key(source, key, value),
+            // Autorepeat doesn't move the axis.
+            Some(ButtonTarget::Axis { axis, value: held }) if value != 2 => {
+                self.hold_axis(source, axis, (value == 1).then_some(held));
+            },
+            _ => {}
+        }
+    }
+
+    /// Puts `source`'s value of `code` at `value` (clamped to the axis), or back at rest for `None`.
+    fn hold_axis(&mut self, source: usize, code: AbsoluteAxisCode, value: Option<i32>) {
+        let Some(axis) = self.axes.get(&code) else {
+            return;
+        };
+        let value = value.map_or(axis.rest(), |value| value.clamp(axis.info.minimum(), axis.info.maximum()));
+        self.set_axis(source, code, value);
+    }
+
+    fn handle_axis(&mut self, source: usize, event: InputEvent) {
+        let from = AbsoluteAxisCode(event.code());
+        self.raw_axes[source].insert(from, event.value());
+        let route = match self.mappings[source].axis(from) {
+            Some(AxisTarget::Axis(route)) => route,
+            Some(AxisTarget::Button(threshold)) => {
+                let Some(range) = self.source_ranges[source].get(&from) else {
+                    return;
+                };
+                let held = self.held_keys[source].contains(&threshold.key);
+                let pressed = threshold.pressed(event.value(), range, held);
+                if pressed != held {
+                    self.handle_key(source, threshold.key, pressed as i32);
+                }
+                return;
+            },
+            Some(AxisTarget::Treadmill) => {
+                let range = self.source_ranges[source].get(&from).copied();
+                if let Some(treadmill) = self.mappings[source].treadmill_mut() {
+                    treadmill.set_speed(event.value(), range.as_ref());
+                }
+                self.update_treadmill(source, Instant::now());
+                return;
+            },
+            None => return,
+        };
+        let Some(axis) = self.axes.get(&route.to) else {
+            self.pending.push(InputEvent::new(EventType::ABSOLUTE.0, route.to.0, event.value()));
+            return;
+        };
+        let mut value = match self.source_ranges[source].get(&from) {
+            Some(range) => rescale(event.value(), range, &axis.info),
+            None => event.value(),
+        };
+        if route.invert {
+            value = invert(value, &axis.info);
+        }
+        let position = shaping::deflection(value, &axis.info);
+        if let Some(position) = self.mappings[source].filter(from, position, Instant::now()) {
+            self.move_axis(source, from, position);
+        }
+    }
+
+    /// Records the (filtered) deflection of the source axis `from` and drives what it is routed to.
+    fn move_axis(&mut self, source: usize, from: AbsoluteAxisCode, position: f64) {
+        self.positions[source].insert(from, position);
+        for (from, position) in self.mappings[source].shape(from, &self.positions[source]) {
+            self.output(source, from, position);
+        }
+    }
+
+    /// Sets the virtual axis the source axis `from` is routed to at deflection `position`.
+    fn output(&mut self, source: usize, from: AbsoluteAxisCode, position: f64) {
+        let Some(AxisTarget::Axis(route)) = self.mappings[source].axis(from) else {
+            return;
+        };
+        let Some(axis) = self.axes.get(&route.to) else {
+            return;
+        };
+        let value = shaping::from_deflection(position, &axis.info);
+        self.set_axis(source, route.to, value);
+    }
+
+    /// Moves the stick (and sprint button) driven by `source`'s treadmill on to `now`.
+    fn update_treadmill(&mut self, source: usize, now: Instant) {
+        let Some(treadmill) = self.mappings[source].treadmill_mut() else {
+            return;
+        };
+        let (deflection, sprinting) = treadmill.update(now);
+        let (stick, sprint_button) = (treadmill.stick(), treadmill.sprint_button());
+        if let Some(axis) = self.axes.get(&stick) {
+            let value = shaping::from_deflection(deflection, &axis.info);
+            self.set_axis(source, stick, value);
+        }
+        if let Some(button) = sprint_button
+            && sprinting != self.held_keys[source].contains(&button) {
+            self.handle_key(source, button, sprinting as i32);
+        }
+    }
+
+    /// When [`Muxer::tick`] next has to run, `None` while nothing is changing over time.
+    pub fn next_tick(&self) -> Option<Instant> {
+        self.mappings.iter()
+            .filter_map(Mapping::next_update)
+            .min()
+    }
+
+    /// Advances everything that changes over time, such as filters and treadmills catching up with
+    /// their input.
+    pub fn tick(&mut self) -> Vec<InputEvent> {
+        let now = Instant::now();
+        for source in 0..self.mappings.len() {
+            for (from, position) in self.mappings[source].update_filters(now) {
+                self.move_axis(source, from, position);
+            }
+            if self.mappings[source].treadmill().and_then(|treadmill| treadmill.next_update()).is_some_and(|due| due <= now) {
+                self.update_treadmill(source, now);
+            }
+        }
+        self.flush()
+    }
+
+    /// Records `value` (already in the virtual axis's units) as what `source` reports for `code`.
+    fn set_axis(&mut self, source: usize, code: AbsoluteAxisCode, value: i32) {
+        let axis = self.axes.get_mut(&code).expect("axis is registered");
+        if axis.values[source] == Some(value) {
+            return;
+        }
+        axis.values[source] = Some(value);
+        axis.writes += 1;
+        axis.written[source] = axis.writes;
+
+        self.update_axis(code)
+    }
+
+    /// Presses on the first source to hold a key and releases only once the last one lets go.
+    fn handle_key(&mut self, source: usize, key: KeyCode, value: i32) {
+        if value == 2 {
+            // Autorepeat is only forwarded while this source actually holds the key.
+            if self.held_keys[source].contains(&key) {
+                self.emit_key(key, value);
+            }
+            return;
+        }
+
+        let was_down = self.key_down(key);
+        if value == 0 {
+            self.held_keys[source].remove(&key);
+        } else {
+            self.held_keys[source].insert(key);
+        }
+        let is_down = self.key_down(key);
+        if was_down != is_down {
+            self.emit_key(key, is_down as i32);
+        }
+    }
+
+    fn key_down(&self, key: KeyCode) -> bool {
+        self.held_keys.iter().any(|keys| keys.contains(&key))
+    }
+
+    fn emit_key(&mut self, key: KeyCode, value: i32) {
+        self.pending.push(InputEvent::new(EventType::KEY.0, key.code(), value));
+    }
+
+    /// Forgets everything `source` reported, e.g. after it disconnected, so its last values don't stick.
+    pub fn detach(&mut self, source: usize) -> Vec<InputEvent> {
+        self.source_ranges[source].clear();
+        self.positions[source].clear();
+        self.mappings[source].reset();
+        self.connected[source] = false;
+        self.raw_axes[source].clear();
+        self.raw_keys[source].clear();
+        for key in std::mem::take(&mut self.held_keys[source]) {
+            if !self.key_down(key) {
+                self.emit_key(key, 0);
+            }
+        }
+
+        let codes: Vec<AbsoluteAxisCode> = self.axes.iter_mut()
+            .filter_map(|(code, axis)| {
+                axis.values[source].take()?;
+                axis.written[source] = 0;
+                Some(*code)
+            })
+            .collect();
+        for code in codes {
+            self.update_axis(code);
+        }
+        self.flush()
+    }
+
+    /// Releases every button and returns every axis to rest, e.g. before the virtual device goes away
+    /// so a game never sees a stuck input.
+    pub fn neutralize(&mut self) -> Vec<InputEvent> {
+        (0..self.held_keys.len()).flat_map(|source| self.detach(source)).collect()
+    }
+
+    fn update_axis(&mut self, code: AbsoluteAxisCode) {
+        if let Some(heading) = self.heading
+            && (code == heading.x || code == heading.y) {
+            return self.update_heading(heading);
+        }
+        let axis = &self.axes[&code];
+        let merged = axis.merged(&self.priorities, None);
+        self.set_output(code, merged);
+    }
+
+    /// Points the stick where the other sources steer it, as far as the treadmill's speed pushes it.
+    /// While the treadmill stands still the stick works as if it wasn't there.
+    fn update_heading(&mut self, heading: Heading) {
+        let (Some(x_axis), Some(y_axis)) = (self.axes.get(&heading.x), self.axes.get(&heading.y)) else {
+            return;
+        };
+        let steer_x = x_axis.merged(&self.priorities, Some(heading.source));
+        let steer_y = y_axis.merged(&self.priorities, Some(heading.source));
+        let speed = y_axis.values[heading.source].map_or(0.0, |value| shaping::deflection(value, &y_axis.info));
+
+        let (x, y) = if speed == 0.0 {
+            (steer_x, steer_y)
+        } else {
+            let (steer_x, steer_y) = (shaping::deflection(steer_x, &x_axis.info), shaping::deflection(steer_y, &y_axis.info));
+            let length = steer_x.hypot(steer_y);
+            // Without a clear direction from the stick, the treadmill goes the way it would on its own.
+            let (dir_x, dir_y) = if length < HEADING_THRESHOLD { (0.0, speed.signum()) } else { (steer_x / length, steer_y / length) };
+            let speed = speed.abs();
+            (shaping::from_deflection(dir_x * speed, &x_axis.info), shaping::from_deflection(dir_y * speed, &y_axis.info))
+        };
+        self.set_output(heading.x, x);
+        self.set_output(heading.y, y);
+    }
+
+    fn set_output(&mut self, code: AbsoluteAxisCode, value: i32) {
+        let axis = self.axes.get_mut(&code).expect("axis is registered");
+        if value != axis.output {
+            axis.output = value;
+            self.pending.push(InputEvent::new(EventType::ABSOLUTE.0, code.0, value));
+        }
+    }
+}
+
+/// Linearly maps `value` from the range of `from` onto the range of `to`, so the source's minimum lands
+/// on the output minimum and its maximum on the output maximum (which also flips signed and unsigned ranges).
+fn rescale(value: i32, from: &AbsInfo, to: &AbsInfo) -> i32 {
+    let (from_min, from_max) = (from.minimum() as f64, from.maximum() as f64);
+    let (to_min, to_max) = (to.minimum() as f64, to.maximum() as f64);
+    if from_max <= from_min || (from_min == to_min && from_max == to_max) {
+        return value.clamp(to.minimum(), to.maximum());
+    }
+    let t = (value as f64 - from_min) / (from_max - from_min);
+    (to_min + t * (to_max - to_min)).round().clamp(to_min, to_max) as i32
+}
+
+/// Mirrors `value` around the middle of the range of `info`.
+fn invert(value: i32, info: &AbsInfo) -> i32 {
+    let mirrored = info.minimum() as i64 + info.maximum() as i64 - value as i64;
+    mirrored.clamp(info.minimum() as i64, info.maximum() as i64) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evdev::AttributeSet;
+
+    fn stick() -> AbsInfo {
+        AbsInfo::new(0, -32768, 32767, 16, 128, 0)
+    }
+
+    fn trigger() -> AbsInfo {
+        AbsInfo::new(0, 0, 1023, 0, 0, 0)
+    }
+
+    /// An axis driven by three sources, with `writes` applied in order like [`Muxer::set_axis`] does.
+    fn driven(policy: AxisPolicy, info: AbsInfo, writes: &[(usize, i32)]) -> Axis {
+        let mut axis = Axis { info, policy, values: vec![None; 3], written: vec![0; 3], writes: 0, output: 0 };
+        for &(source, value) in writes {
+            axis.values[source] = Some(value);
+            axis.writes += 1;
+            axis.written[source] = axis.writes;
+        }
+        axis
+    }
+
+    const PRIORITIES: [i32; 3] = [3, 2, 1];
+
+    #[test]
+    fn untouched_axes_rest() {
+        for policy in [
+            AxisPolicy::LastWriterWins,
+            AxisPolicy::LargestMagnitudeWins,
+            AxisPolicy::SumAndClamp,
+            AxisPolicy::PriorityOrdered,
+            AxisPolicy::Average,
+        ] {
+            assert_eq!(driven(policy, stick(), &[]).merged(&PRIORITIES, None), 0, "{:?}", policy);
+            assert_eq!(driven(policy, trigger(), &[]).merged(&PRIORITIES, None), 0, "{:?}", policy);
+        }
+    }
+
+    #[test]
+    fn mirrored_unsigned_sticks_rest_centred() {
+        let info = shaping::at_rest(AbsoluteAxisCode::ABS_X, &AbsInfo::new(0, 0, 255, 0, 0, 0));
+        for policy in [AxisPolicy::LastWriterWins, AxisPolicy::SumAndClamp, AxisPolicy::Average] {
+            assert_eq!(driven(policy, info, &[]).merged(&PRIORITIES, None), 128, "{:?}", policy);
+        }
+        let axis = driven(AxisPolicy::SumAndClamp, info, &[(0, 200), (1, 100)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 172);
+    }
+
+    #[test]
+    fn last_writer_wins() {
+        let axis = driven(AxisPolicy::LastWriterWins, stick(), &[(0, -20000), (1, 5000), (0, -10000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), -10000);
+        // Without the last writer the one before it decides.
+        assert_eq!(axis.merged(&PRIORITIES, Some(0)), 5000);
+    }
+
+    #[test]
+    fn largest_magnitude_wins() {
+        let axis = driven(AxisPolicy::LargestMagnitudeWins, stick(), &[(0, -20000), (1, 25000), (2, 100)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 25000);
+        let axis = driven(AxisPolicy::LargestMagnitudeWins, trigger(), &[(0, 300), (1, 900)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 900);
+    }
+
+    #[test]
+    fn sum_and_clamp() {
+        let axis = driven(AxisPolicy::SumAndClamp, stick(), &[(0, -20000), (1, 5000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), -15000);
+        let axis = driven(AxisPolicy::SumAndClamp, stick(), &[(0, 20000), (1, 20000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 32767);
+        let axis = driven(AxisPolicy::SumAndClamp, trigger(), &[(0, 600), (1, 600)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 1023);
+    }
+
+    #[test]
+    fn priority_ordered_skips_sources_at_rest() {
+        let axis = driven(AxisPolicy::PriorityOrdered, stick(), &[(0, 0), (1, 5000), (2, -9000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 5000);
+        // A resting stick's jitter doesn't hold back lower priority sources.
+        let axis = driven(AxisPolicy::PriorityOrdered, stick(), &[(0, 129), (1, 5000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 5000);
+        let axis = driven(AxisPolicy::PriorityOrdered, stick(), &[(0, -1500), (1, 5000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 5000);
+        let axis = driven(AxisPolicy::PriorityOrdered, stick(), &[(0, -2000), (1, 5000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), -2000);
+        // Equal priorities go by list order.
+        assert_eq!(axis.merged(&[1, 1, 1], None), -2000);
+        let axis = driven(AxisPolicy::PriorityOrdered, trigger(), &[(0, 20), (1, 40)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), 0);
+    }
+
+    #[test]
+    fn average_of_reporting_sources() {
+        let axis = driven(AxisPolicy::Average, stick(), &[(0, -20000), (2, 10000)]);
+        assert_eq!(axis.merged(&PRIORITIES, None), -5000);
+        assert_eq!(axis.merged(&PRIORITIES, Some(0)), 10000);
+    }
+
+    #[test]
+    fn rescales_between_unsigned_and_signed_ranges() {
+        let dualshock_trigger = AbsInfo::new(0, 0, 255, 0, 0, 0);
+        assert_eq!(rescale(0, &dualshock_trigger, &trigger()), 0);
+        assert_eq!(rescale(255, &dualshock_trigger, &trigger()), 1023);
+        assert_eq!(rescale(128, &dualshock_trigger, &trigger()), 514);
+
+        let small_stick = AbsInfo::new(0, -128, 127, 0, 0, 0);
+        assert_eq!(rescale(-128, &small_stick, &stick()), -32768);
+        assert_eq!(rescale(127, &small_stick, &stick()), 32767);
+        assert_eq!(rescale(0, &small_stick, &stick()), 128);
+
+        let sensor = AbsInfo::new(0, 0, 1000, 0, 0, 0);
+        assert_eq!(rescale(0, &sensor, &stick()), -32768);
+        assert_eq!(rescale(1000, &sensor, &stick()), 32767);
+    }
+
+    #[test]
+    fn rescale_clamps_and_passes_through_matching_or_broken_ranges() {
+        assert_eq!(rescale(40000, &stick(), &stick()), 32767);
+        let empty = AbsInfo::new(0, 5, 5, 0, 0, 0);
+        assert_eq!(rescale(2000, &empty, &trigger()), 1023);
+        assert_eq!(rescale(-5, &AbsInfo::new(0, 0, 255, 0, 0, 0), &trigger()), 0);
+    }
+
+    #[test]
+    fn inverts_around_the_middle() {
+        assert_eq!(invert(-32768, &stick()), 32767);
+        assert_eq!(invert(32767, &stick()), -32768);
+        assert_eq!(invert(0, &stick()), -1);
+        assert_eq!(invert(0, &trigger()), 1023);
+        assert_eq!(invert(1023, &trigger()), 0);
+    }
+
+    /// A muxer with a stick (`ABS_X`, `ABS_Y`) and a trigger (`ABS_Z`), one source per device config.
+    fn muxer(devices: &[&str]) -> Muxer {
+        let axes = [(AbsoluteAxisCode::ABS_X, stick()), (AbsoluteAxisCode::ABS_Y, stick()), (AbsoluteAxisCode::ABS_Z, trigger())];
+        let mappings = devices.iter()
+            .map(|text| Mapping::new(&toml::from_str(text).expect("parses")))
+            .collect();
+        Muxer::new(&axes, |_| AxisPolicy::LastWriterWins, vec![0; devices.len()], mappings)
+    }
+
+    fn attach(muxer: &mut Muxer, source: usize, axes: &[(AbsoluteAxisCode, AbsInfo)]) {
+        muxer.resync(source, &AttributeSet::<KeyCode>::new(), axes);
+    }
+
+    fn move_to(muxer: &mut Muxer, source: usize, moves: &[(AbsoluteAxisCode, i32)]) -> Vec<(u16, u16, i32)> {
+        let events: Vec<InputEvent> = moves.iter()
+            .map(|(code, value)| InputEvent::new(EventType::ABSOLUTE.0, code.0, *value))
+            .collect();
+        summary(&muxer.handle_frame(source, &events))
+    }
+
+    fn press(muxer: &mut Muxer, source: usize, key: KeyCode, value: i32) -> Vec<(u16, u16, i32)> {
+        summary(&muxer.handle_frame(source, &[InputEvent::new(EventType::KEY.0, key.code(), value)]))
+    }
+
+    fn summary(events: &[InputEvent]) -> Vec<(u16, u16, i32)> {
+        events.iter().map(|event| (event.event_type().0, event.code(), event.value())).collect()
+    }
+
+    fn stick_output(muxer: &Muxer) -> (i32, i32) {
+        (muxer.axes[&AbsoluteAxisCode::ABS_X].output, muxer.axes[&AbsoluteAxisCode::ABS_Y].output)
+    }
+
+    const BELT: &str = "name = \"Belt\"\n[treadmill]\naxis = \"ABS_RX\"\nheading = true\nsmoothing = 0.0";
+    const PAD: &str = "name = \"Pad\"";
+
+    fn belt_and_pad() -> Muxer {
+        let mut muxer = muxer(&[BELT, PAD]);
+        attach(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, AbsInfo::new(0, 0, 1000, 0, 0, 0))]);
+        muxer
+    }
+
+    #[test]
+    fn heading_passes_steering_through_while_the_treadmill_stands_still() {
+        let mut muxer = belt_and_pad();
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, 20000), (AbsoluteAxisCode::ABS_Y, -3000)]);
+        assert_eq!(stick_output(&muxer), (20000, -3000));
+
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 500)]);
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 0)]);
+        assert_eq!(stick_output(&muxer), (20000, -3000));
+    }
+
+    #[test]
+    fn heading_goes_straight_ahead_without_a_clear_direction() {
+        let mut muxer = belt_and_pad();
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 500)]);
+        assert_eq!(stick_output(&muxer), (0, -16384));
+        // Below the threshold the stick's own offset doesn't count.
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, 6000), (AbsoluteAxisCode::ABS_Y, 3000)]);
+        assert_eq!(stick_output(&muxer), (0, -16384));
+    }
+
+    #[test]
+    fn heading_points_where_the_stick_steers() {
+        let mut muxer = belt_and_pad();
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 500)]);
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, 32767)]);
+        assert_eq!(stick_output(&muxer), (16384, 0));
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, 0), (AbsoluteAxisCode::ABS_Y, 32767)]);
+        assert_eq!(stick_output(&muxer), (0, 16384));
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, -32768), (AbsoluteAxisCode::ABS_Y, 0)]);
+        assert_eq!(stick_output(&muxer), (-16384, 0));
+    }
+
+    #[test]
+    fn heading_takes_the_magnitude_from_the_treadmill() {
+        let mut muxer = belt_and_pad();
+        // However far the stick is pushed, the treadmill decides how far the output goes.
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_X, 8200), (AbsoluteAxisCode::ABS_Y, 8200)]);
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 1000)]);
+        let diagonal = shaping::from_deflection(0.5f64.sqrt(), &stick());
+        assert_eq!(stick_output(&muxer), (diagonal, diagonal));
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 250)]);
+        let diagonal = shaping::from_deflection(0.25 * 0.5f64.sqrt(), &stick());
+        assert_eq!(stick_output(&muxer), (diagonal, diagonal));
+    }
+
+    #[test]
+    fn axis_buttons_press_and_release_with_hysteresis() {
+        let mut muxer = muxer(&["name = \"Pad\"\n[axes]\nABS_Z = { button = \"BTN_TL2\" }"]);
+        attach(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, trigger())]);
+        let key = |value| vec![(EventType::KEY.0, KeyCode::BTN_TL2.code(), value)];
+        assert_eq!(move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, 450)]), vec![]);
+        assert_eq!(move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, 520)]), key(1));
+        // Back under `press` but still over `release`.
+        assert_eq!(move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, 450)]), vec![]);
+        assert_eq!(move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, 400)]), key(0));
+        assert_eq!(move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_Z, 450)]), vec![]);
+        // The axis itself never reaches the virtual device.
+        assert_eq!(muxer.axes[&AbsoluteAxisCode::ABS_Z].output, 0);
+    }
+
+    #[test]
+    fn threshold_is_measured_from_rest() {
+        let mapping = Mapping::new(&toml::from_str("name = \"Pad\"\n[axes]\nABS_X = { button = \"BTN_WEST\" }").expect("parses"));
+        let Some(AxisTarget::Button(threshold)) = mapping.axis(AbsoluteAxisCode::ABS_X) else {
+            panic!("not a button");
+        };
+        let range = shaping::at_rest(AbsoluteAxisCode::ABS_X, &AbsInfo::new(0, 0, 255, 0, 0, 0));
+        assert!(!threshold.pressed(128, &range, false));
+        assert!(threshold.pressed(10, &range, false));
+        assert!(threshold.pressed(250, &range, false));
+        assert!(threshold.pressed(200, &range, true));
+        assert!(!threshold.pressed(170, &range, true));
+    }
+
+    #[test]
+    fn buttons_press_on_the_first_source_and_release_on_the_last() {
+        let mut muxer = muxer(&[PAD, PAD]);
+        let south = |value| vec![(EventType::KEY.0, KeyCode::BTN_SOUTH.code(), value)];
+        assert_eq!(press(&mut muxer, 0, KeyCode::BTN_SOUTH, 1), south(1));
+        assert_eq!(press(&mut muxer, 1, KeyCode::BTN_SOUTH, 1), vec![]);
+        assert_eq!(press(&mut muxer, 0, KeyCode::BTN_SOUTH, 0), vec![]);
+        assert_eq!(press(&mut muxer, 1, KeyCode::BTN_SOUTH, 0), south(0));
+    }
+
+    #[test]
+    fn autorepeat_only_comes_from_a_source_holding_the_button() {
+        let mut muxer = muxer(&[PAD, PAD]);
+        let south = |value| vec![(EventType::KEY.0, KeyCode::BTN_SOUTH.code(), value)];
+        press(&mut muxer, 0, KeyCode::BTN_SOUTH, 1);
+        assert_eq!(press(&mut muxer, 0, KeyCode::BTN_SOUTH, 2), south(2));
+        assert_eq!(press(&mut muxer, 1, KeyCode::BTN_SOUTH, 2), vec![]);
+    }
+
+    #[test]
+    fn detaching_releases_only_what_nobody_else_holds() {
+        let mut muxer = muxer(&[PAD, PAD]);
+        press(&mut muxer, 0, KeyCode::BTN_SOUTH, 1);
+        press(&mut muxer, 0, KeyCode::BTN_EAST, 1);
+        press(&mut muxer, 1, KeyCode::BTN_SOUTH, 1);
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_X, 20000)]);
+        assert_eq!(summary(&muxer.detach(0)), vec![
+            (EventType::KEY.0, KeyCode::BTN_EAST.code(), 0),
+            (EventType::ABSOLUTE.0, AbsoluteAxisCode::ABS_X.0, 0),
+        ]);
+        assert_eq!(summary(&muxer.neutralize()), vec![(EventType::KEY.0, KeyCode::BTN_SOUTH.code(), 0)]);
+    }
+
+    #[test]
+    fn snapshot_shows_what_sources_sent_before_mapping() {
+        let mut muxer = muxer(&[BELT, "name = \"Pad\"\n[axes]\nABS_RY = { to = \"ABS_X\" }\n[buttons]\nBTN_SOUTH = \"BTN_EAST\""]);
+        attach(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, AbsInfo::new(0, 0, 1000, 0, 0, 0))]);
+        move_to(&mut muxer, 0, &[(AbsoluteAxisCode::ABS_RX, 500)]);
+        move_to(&mut muxer, 1, &[(AbsoluteAxisCode::ABS_RY, 32767)]);
+        press(&mut muxer, 1, KeyCode::BTN_SOUTH, 1);
+
+        let snapshot = muxer.snapshot();
+        assert_eq!(snapshot.source_axes, vec![
+            vec![(AbsoluteAxisCode::ABS_RX, 500)],
+            vec![(AbsoluteAxisCode::ABS_RY, 32767)],
+        ]);
+        assert_eq!(snapshot.output_axes, vec![
+            (AbsoluteAxisCode::ABS_X, 16384),
+            (AbsoluteAxisCode::ABS_Y, 0),
+            (AbsoluteAxisCode::ABS_Z, 0),
+        ]);
+        assert_eq!(snapshot.held_keys, vec![vec![], vec![KeyCode::BTN_SOUTH]]);
+        assert_eq!(snapshot.output_keys, vec![KeyCode::BTN_EAST]);
+
+        muxer.detach(1);
+        assert_eq!(muxer.snapshot().source_axes[1], vec![]);
+    }
+}
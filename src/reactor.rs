@@ -0,0 +1,359 @@
+use crate::config::DeviceConfig;
+use crate::discovery::{find_device, HiddenNode, Matcher};
+use crate::ff::ForceFeedback;
+use crate::hotplug::{Hotplug, FALLBACK_INTERVAL};
+use crate::monitor::Monitor;
+use crate::mux::Muxer;
+use crate::shutdown::Shutdown;
+use evdev::raw_stream::RawDevice;
+use evdev::uinput::VirtualDevice;
+use evdev::{AbsInfo, AbsoluteAxisCode, EventSummary, InputEvent, SynchronizationCode};
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout};
+use std::collections::HashSet;
+use std::error::Error;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// How long to wait before looking for a device again after its handler failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(3);
+
+// epoll tokens, source `i` uses `FIRST_SOURCE + i`.
+const SHUTDOWN: u64 = 0;
+const HOTPLUG: u64 = 1;
+const VIRTUAL_DEVICE: u64 = 2;
+const MONITOR: u64 = 3;
+const FIRST_SOURCE: u64 = 4;
+
+/// One configured source and the device currently attached to it, if any.
+struct Source {
+    config: DeviceConfig,
+    matcher: Matcher,
+    attached: Option<Attached>,
+    /// Set after the handler failed, the device is only looked for again once this passed.
+    retry_at: Option<Instant>,
+}
+
+struct Attached {
+    path: PathBuf,
+    device: RawDevice,
+    name: String,
+    /// Events are collected up to their SYN_REPORT so a report is applied as a whole, never
+    /// interleaved with other sources.
+    frame: Vec<InputEvent>,
+    /// The kernel buffer overflowed, the next SYN_REPORT resyncs instead of applying the frame.
+    dropped: bool,
+    _hidden: Option<HiddenNode>,
+}
+
+/// Runs the muxer on a single thread: waits on every source, the virtual device (for force
+/// feedback), hotplug notifications, the monitor's log and the shutdown signal at once and handles whatever is ready in
+/// order. It owns the muxer and the virtual device, so nothing needs locking.
+pub struct Reactor {
+    epoll: Epoll,
+    muxer: Muxer,
+    /// Where the muxer's output goes.
+    device: VirtualDevice,
+    /// The virtual device's event nodes, never attached as a source.
+    own_nodes: HashSet<PathBuf>,
+    ff: ForceFeedback,
+    hotplug: Hotplug,
+    shutdown: Shutdown,
+    sources: Vec<Source>,
+    monitor: Option<Monitor>,
+    /// When to look for missing devices again, only used if `/dev/input` can't be watched.
+    next_scan: Option<Instant>,
+}
+
+impl Reactor {
+    /// `forward_ff` services force feedback requests games make on `device`.
+    pub fn new(
+        muxer: Muxer,
+        mut device: VirtualDevice,
+        forward_ff: bool,
+        hotplug: Hotplug,
+        shutdown: Shutdown,
+        sources: Vec<(DeviceConfig, Matcher)>,
+        monitor: Option<Monitor>,
+    ) -> io::Result<Self> {
+        let epoll = Epoll::new(EpollCreateFlags::EPOLL_CLOEXEC)?;
+        epoll.add(&shutdown, EpollEvent::new(EpollFlags::EPOLLIN, SHUTDOWN))?;
+        if let Some(fd) = hotplug.fd() {
+            epoll.add(fd, EpollEvent::new(EpollFlags::EPOLLIN, HOTPLUG))?;
+        }
+        if forward_ff {
+            epoll.add(&device, EpollEvent::new(EpollFlags::EPOLLIN, VIRTUAL_DEVICE))?;
+        }
+        if let Some(monitor) = &monitor {
+            epoll.add(monitor, EpollEvent::new(EpollFlags::EPOLLIN, MONITOR))?;
+        }
+        // Our own output may well match a source's matcher (presets even copy a real pad's name and IDs).
+        let own_nodes = device.enumerate_dev_nodes_blocking()?.collect::<io::Result<HashSet<_>>>()?;
+        let next_scan = hotplug.fd().is_none().then(|| Instant::now() + FALLBACK_INTERVAL);
+        let sources = sources.into_iter()
+            .map(|(config, matcher)| Source { config, matcher, attached: None, retry_at: None })
+            .collect();
+        Ok(Self { epoll, muxer, device, own_nodes, ff: ForceFeedback::default(), hotplug, shutdown, sources, monitor, next_scan })
+    }
+
+    /// Handles events until a shutdown is requested, then releases every source and returns the
+    /// virtual device with all of its inputs at rest.
+    pub fn run(mut self) -> Result<VirtualDevice, Box<dyn Error>> {
+        self.attach_missing();
+        let mut events = [EpollEvent::empty(); 16];
+        while !self.shutdown.is_requested() {
+            let timeout = match self.next_deadline() {
+                Some(deadline) => EpollTimeout::try_from(timeout_until(deadline)).unwrap_or(EpollTimeout::MAX),
+                None => EpollTimeout::NONE,
+            };
+            let count = match self.epoll.wait(&mut events, timeout) {
+                Ok(count) => count,
+                Err(Errno::EINTR) => 0,
+                Err(e) => return Err(e.into()),
+            };
+            for event in &events[..count] {
+                match event.data() {
+                    SHUTDOWN => {}
+                    HOTPLUG => {
+                        if self.hotplug.changed()? {
+                            self.attach_missing();
+                        }
+                    },
+                    VIRTUAL_DEVICE => {
+                        if let Err(e) = self.ff.handle_requests(&mut self.device) {
+                            eprintln!("Force feedback handling failed: {}", e);
+                        }
+                    },
+                    MONITOR => {
+                        if let Some(monitor) = &mut self.monitor {
+                            monitor.read_log();
+                        }
+                    },
+                    token => self.read_source((token - FIRST_SOURCE) as usize),
+                }
+            }
+            self.run_timers();
+        }
+
+        println!("Shutting down...");
+        for source in &mut self.sources {
+            if let Some(mut attached) = source.attached.take()
+                && source.config.grab
+                && let Err(e) = attached.device.ungrab() {
+                eprintln!("[{}] Failed to release the grab: {}", attached.name, e);
+            }
+        }
+        emit(&mut self.device, &self.muxer.neutralize())?;
+        Ok(self.device)
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.sources.iter()
+            .filter_map(|source| source.retry_at)
+            .chain(self.next_scan)
+            .chain(self.monitor.as_ref().map(Monitor::next_draw))
+            .chain(self.muxer.next_tick())
+            .min()
+    }
+
+    fn run_timers(&mut self) {
+        let now = Instant::now();
+        for index in 0..self.sources.len() {
+            if self.sources[index].retry_at.is_some_and(|retry_at| retry_at <= now) {
+                self.sources[index].retry_at = None;
+                self.attach(index);
+            }
+        }
+        if self.next_scan.is_some_and(|next_scan| next_scan <= now) {
+            self.next_scan = Some(now + FALLBACK_INTERVAL);
+            self.attach_missing();
+        }
+        if self.muxer.next_tick().is_some_and(|next_tick| next_tick <= now)
+            && let Err(e) = emit(&mut self.device, &self.muxer.tick()) {
+            eprintln!("Failed to update the virtual device: {}", e);
+        }
+        if let Some(monitor) = &mut self.monitor
+            && monitor.next_draw() <= now {
+            monitor.draw(&self.muxer.snapshot());
+        }
+    }
+
+    /// Looks for the device of every source that has none and isn't waiting to retry.
+    fn attach_missing(&mut self) {
+        for index in 0..self.sources.len() {
+            let source = &self.sources[index];
+            if source.attached.is_none() && source.retry_at.is_none() {
+                self.attach(index);
+            }
+        }
+    }
+
+    fn attach(&mut self, index: usize) {
+        let claimed: HashSet<PathBuf> = self.sources.iter()
+            .filter_map(|source| source.attached.as_ref())
+            .map(|attached| attached.path.clone())
+            .chain(self.own_nodes.iter().cloned())
+            .collect();
+        match find_device(&self.sources[index].matcher, &claimed) {
+            Ok((path, device)) => {
+                if let Err(e) = self.start(index, path, device) {
+                    self.fail(index, &*e);
+                }
+            },
+            Err(e) => println!("{} Waiting for it to be connected...", e),
+        }
+    }
+
+    fn start(&mut self, index: usize, path: PathBuf, mut device: RawDevice) -> Result<(), Box<dyn Error>> {
+        let config = &self.sources[index].config;
+        let name = device.name().unwrap_or("Unknown").to_string();
+        println!("Starting input stream for: {}", name);
+        if config.grab {
+            device.grab()?;
+        } else {
+            println!("[{}] Not grabbed, other programs still see its input.", name);
+        }
+        let hidden = if config.hide {
+            HiddenNode::hide(&path)
+                .inspect_err(|e| eprintln!("[{}] Could not hide {} from other programs: {}", name, path.display(), e))
+                .ok()
+        } else {
+            None
+        };
+        let forward_ff = config.force_feedback && device.supported_ff().is_some();
+        set_nonblocking(&device)?;
+
+        let attached = self.sources[index].attached.insert(Attached {
+            path,
+            device,
+            name,
+            frame: Vec::new(),
+            dropped: false,
+            _hidden: hidden,
+        });
+        self.epoll.add(&attached.device, EpollEvent::new(EpollFlags::EPOLLIN, FIRST_SOURCE + index as u64))?;
+        emit(&mut self.device, &resync(index, &attached.device, &mut self.muxer)?)?;
+        if forward_ff {
+            self.ff.attach(index, &attached.device)?;
+        }
+        Ok(())
+    }
+
+    fn read_source(&mut self, index: usize) {
+        let Err(e) = self.process_events(index) else {
+            return;
+        };
+        if is_disconnect(&*e) {
+            println!("[{}] Disconnected.", self.sources[index].matcher);
+            self.detach(index);
+            self.attach(index);
+        } else {
+            self.fail(index, &*e);
+        }
+    }
+
+    fn process_events(&mut self, index: usize) -> Result<(), Box<dyn Error>> {
+        let Some(attached) = &mut self.sources[index].attached else {
+            return Ok(());
+        };
+        let events: Vec<_> = match attached.device.fetch_events() {
+            Ok(events) => events.collect(),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+        for event in events {
+            match event.destructure() {
+                EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, _) => {
+                    if attached.dropped {
+                        // Whatever we saw since is incomplete. Start over from the real state.
+                        attached.dropped = false;
+                        emit(&mut self.device, &resync(index, &attached.device, &mut self.muxer)?)?;
+                    } else {
+                        emit(&mut self.device, &self.muxer.handle_frame(index, &attached.frame))?;
+                    }
+                    attached.frame.clear();
+                },
+                EventSummary::Synchronization(_, SynchronizationCode::SYN_DROPPED, _) => {
+                    eprintln!("[{}] Events were dropped, resyncing state.", attached.name);
+                    attached.dropped = true;
+                    attached.frame.clear();
+                },
+                _ if !attached.dropped => attached.frame.push(event),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops the source's device after an error and looks for it again a bit later.
+    fn fail(&mut self, index: usize, error: &dyn Error) {
+        eprintln!("[{}] Handler exited (reconnecting in {}s): {}", self.sources[index].matcher, RETRY_INTERVAL.as_secs(), error);
+        self.detach(index);
+        self.sources[index].retry_at = Some(Instant::now() + RETRY_INTERVAL);
+    }
+
+    fn detach(&mut self, index: usize) {
+        let source = &mut self.sources[index];
+        if let Some(attached) = source.attached.take() {
+            // The force feedback handle shares the open file, which would keep it registered.
+            let _ = self.epoll.delete(&attached.device);
+        }
+        self.ff.detach(index);
+        if let Err(e) = emit(&mut self.device, &self.muxer.detach(index)) {
+            eprintln!("[{}] Failed to release its inputs: {}", source.matcher, e);
+        }
+    }
+}
+
+/// Replaces everything the muxer knows about `source` with the device's current state from the
+/// kernel. Returns the resulting output events.
+fn resync(source: usize, source_device: &RawDevice, muxer: &mut Muxer) -> io::Result<Vec<InputEvent>> {
+    let keys = source_device.get_key_state()?;
+    let axes: Vec<(AbsoluteAxisCode, AbsInfo)> = source_device.get_absinfo()?.collect();
+    Ok(muxer.resync(source, &keys, &axes))
+}
+
+/// Writes one report of the muxer's output, if there is anything to report.
+fn emit(device: &mut VirtualDevice, events: &[InputEvent]) -> io::Result<()> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    device.emit(events)
+}
+
+/// The time left until `deadline`, rounded up to whole milliseconds: epoll would truncate it, wake
+/// up just before the deadline and spin until it passed.
+fn timeout_until(deadline: Instant) -> Duration {
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    Duration::from_millis(remaining.as_micros().div_ceil(1000) as u64)
+}
+
+/// Reads then return what is buffered instead of waiting for more.
+fn set_nonblocking(fd: impl AsFd) -> io::Result<()> {
+    let fd = fd.as_fd().as_raw_fd();
+    let flags = OFlag::from_bits_retain(fcntl(fd, FcntlArg::F_GETFL)?);
+    fcntl(fd, FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK))?;
+    Ok(())
+}
+
+/// Whether reading stopped because the device was unplugged rather than because something failed.
+fn is_disconnect(error: &(dyn Error + 'static)) -> bool {
+    error.downcast_ref::<io::Error>()
+        .and_then(io::Error::raw_os_error)
+        .is_some_and(|code| code == Errno::ENODEV as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_round_up() {
+        assert_eq!(timeout_until(Instant::now() + Duration::from_micros(300)), Duration::from_millis(1));
+        assert!(timeout_until(Instant::now() + Duration::from_micros(10_500)) >= Duration::from_millis(10));
+        assert_eq!(timeout_until(Instant::now() - Duration::from_millis(5)), Duration::ZERO);
+    }
+}
@@ -0,0 +1,431 @@
+use evdev::{AbsInfo, AbsoluteAxisCode};
+use serde::Deserialize;
+use std::time::{Duration, Instant};
+
+/// Largest `rate` or `strength` a curve takes. Beyond it the math overflows and the curve is a step anyway.
+const MAX_STEEPNESS: f64 = 50.0;
+/// How often a [`Smoother`] is stepped while it is still catching up with its target.
+const TICK_INTERVAL: Duration = Duration::from_millis(10);
+/// Differences below this count as caught up.
+const EPSILON: f64 = 1e-3;
+
+/// Axes that centre when let go, whatever their range.
+const CENTRED_AXES: &[AbsoluteAxisCode] = &[
+    AbsoluteAxisCode::ABS_X,
+    AbsoluteAxisCode::ABS_Y,
+    AbsoluteAxisCode::ABS_RX,
+    AbsoluteAxisCode::ABS_RY,
+    AbsoluteAxisCode::ABS_RUDDER,
+    AbsoluteAxisCode::ABS_WHEEL,
+    AbsoluteAxisCode::ABS_HAT0X,
+    AbsoluteAxisCode::ABS_HAT0Y,
+    AbsoluteAxisCode::ABS_HAT1X,
+    AbsoluteAxisCode::ABS_HAT1Y,
+    AbsoluteAxisCode::ABS_HAT2X,
+    AbsoluteAxisCode::ABS_HAT2Y,
+    AbsoluteAxisCode::ABS_HAT3X,
+    AbsoluteAxisCode::ABS_HAT3Y,
+];
+
+/// `info` with its value set to where the axis `code` sits when nothing touches it: 0 if the range
+/// is signed, the middle of it for sticks and hats reporting e.g. 0..255, and the end nearest to 0
+/// for anything else, like triggers.
+///
+/// Ranges the muxer keeps carry their rest position as the value, which the functions below rely on.
+pub fn at_rest(code: AbsoluteAxisCode, info: &AbsInfo) -> AbsInfo {
+    let (min, max) = (info.minimum(), info.maximum());
+    let rest = if min < 0 && max > 0 {
+        0
+    } else if CENTRED_AXES.contains(&code) {
+        ((min as i64 + max as i64 + 1) / 2) as i32
+    } else {
+        0.clamp(min, max)
+    };
+    AbsInfo::new(rest, min, max, info.fuzz(), info.flat(), info.resolution())
+}
+
+/// Where `value` sits between the rest position of `info` (its value, see [`at_rest`]) and the end
+/// of the range on its side: 0 at rest, 1 at the maximum and -1 at the minimum. Axes resting at
+/// their minimum only go from 0 to 1.
+pub fn deflection(value: i32, info: &AbsInfo) -> f64 {
+    let rest = info.value() as f64;
+    let end = if value as f64 >= rest { info.maximum() } else { info.minimum() } as f64;
+    if end == rest {
+        return 0.0;
+    }
+    (value as f64 - rest) / (end - rest).abs()
+}
+
+/// The inverse of [`deflection`].
+pub fn from_deflection(position: f64, info: &AbsInfo) -> i32 {
+    let rest = info.value() as f64;
+    let end = if position >= 0.0 { info.maximum() } else { info.minimum() } as f64;
+    (rest + position.abs() * (end - rest)).round().clamp(info.minimum() as f64, info.maximum() as f64) as i32
+}
+
+/// Ignores noise around rest (`inner`) and treats the last bit of travel (`outer`) as fully
+/// deflected, stretching what is left in between over the whole range.
+#[derive(Clone, Copy)]
+pub struct Deadzone {
+    inner: f64,
+    outer: f64,
+}
+
+impl Deadzone {
+    pub fn new(inner: f64, outer: f64) -> Self {
+        Self { inner, outer }
+    }
+
+    /// Applies the deadzone to one axis on its own.
+    pub fn apply(&self, position: f64) -> f64 {
+        let magnitude = position.abs();
+        if magnitude <= self.inner {
+            return 0.0;
+        }
+        let scaled = ((magnitude - self.inner) / (1.0 - self.inner - self.outer)).min(1.0);
+        scaled.copysign(position)
+    }
+
+    /// Applies the deadzone to the length of a stick's (x, y) vector, keeping its direction, so
+    /// small movements along the diagonal don't snap to an axis.
+    pub fn apply_radial(&self, x: f64, y: f64) -> (f64, f64) {
+        let magnitude = x.hypot(y);
+        if magnitude <= self.inner {
+            return (0.0, 0.0);
+        }
+        let scale = self.apply(magnitude) / magnitude;
+        ((x * scale).clamp(-1.0, 1.0), (y * scale).clamp(-1.0, 1.0))
+    }
+}
+
+/// Follows a target over time: an exponential moving average, then a limit on how fast the output
+/// may change. It is stepped whenever the target changes and every [`Smoother::next_update`] in
+/// between until it caught up.
+pub struct Smoother {
+    /// Time constant of the moving average, in seconds (0 turns it off).
+    time_constant: f64,
+    /// Largest output change per second while rising and falling, unlimited if `None`.
+    rise: Option<f64>,
+    fall: Option<f64>,
+
+    target: f64,
+    smoothed: f64,
+    output: f64,
+    last_update: Option<Instant>,
+}
+
+impl Smoother {
+    pub fn new(time_constant: f64, rise: Option<f64>, fall: Option<f64>) -> Self {
+        Self { time_constant, rise, fall, target: 0.0, smoothed: 0.0, output: 0.0, last_update: None }
+    }
+
+    /// Sets what to move towards from now on.
+    pub fn set_target(&mut self, target: f64) {
+        if self.next_update().is_none() {
+            // Time spent caught up doesn't count, smoothing and rate limits start from the new target.
+            self.last_update = None;
+        }
+        self.target = target;
+    }
+
+    /// Sets the target and the output at once, e.g. for a first reading there is nothing to smooth from.
+    pub fn jump_to(&mut self, target: f64) {
+        self.target = target;
+        self.smoothed = target;
+        self.output = target;
+        self.last_update = None;
+    }
+
+    /// Moves the output on to `now` and returns it.
+    pub fn update(&mut self, now: Instant) -> f64 {
+        let elapsed = self.last_update.map_or(0.0, |last| now.saturating_duration_since(last).as_secs_f64());
+        self.last_update = Some(now);
+
+        self.smoothed = if self.time_constant > 0.0 {
+            self.smoothed + (self.target - self.smoothed) * (1.0 - (-elapsed / self.time_constant).exp())
+        } else {
+            self.target
+        };
+        if (self.target - self.smoothed).abs() < EPSILON {
+            self.smoothed = self.target;
+        }
+
+        let rate = if self.smoothed > self.output { self.rise } else { self.fall };
+        let step = rate.map_or(f64::INFINITY, |rate| rate * elapsed);
+        let difference = self.smoothed - self.output;
+        self.output = if difference.abs() <= step { self.smoothed } else { self.output + step.copysign(difference) };
+        self.output
+    }
+
+    /// When the output next needs to move, `None` once it caught up with the target.
+    pub fn next_update(&self) -> Option<Instant> {
+        let settling = self.smoothed != self.target || self.output != self.smoothed;
+        settling.then(|| self.last_update.map_or_else(Instant::now, |last| last + TICK_INTERVAL))
+    }
+
+    /// Back to 0, with nothing to catch up on.
+    pub fn reset(&mut self) {
+        self.jump_to(0.0);
+    }
+}
+
+/// Bends how far an axis is pushed into how far the output moves, e.g. for finer control near rest.
+/// Works on the distance from rest (0 to 1), the direction is kept.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+pub enum Curve {
+    /// `distance ^ exponent`: above 1 is softer near rest, below 1 more sensitive.
+    Power { exponent: f64 },
+    /// Rises slowly and then ever faster for a positive `rate`, the other way round for a negative one.
+    Exponential { rate: f64 },
+    /// Soft near rest and near the end, steep in the middle. Higher `strength` makes it more pronounced.
+    #[serde(rename = "s-curve")]
+    Sigmoid { strength: f64 },
+    /// Linear interpolation between `[input, output]` points, both from 0 to 1. Rest always stays at
+    /// rest: below the first point the table runs from (0, 0).
+    Table { points: Vec<(f64, f64)> },
+}
+
+impl Curve {
+    pub fn apply(&self, position: f64) -> f64 {
+        let x = position.abs().min(1.0);
+        let y = match self {
+            Curve::Power { exponent } => x.powf(*exponent),
+            Curve::Exponential { rate } if *rate == 0.0 => x,
+            Curve::Exponential { rate } => (rate * x).exp_m1() / rate.exp_m1(),
+            Curve::Sigmoid { strength } => {
+                let (rising, falling) = (x.powf(*strength), (1.0 - x).powf(*strength));
+                rising / (rising + falling)
+            },
+            Curve::Table { points } => interpolate(points, x),
+        };
+        y.clamp(0.0, 1.0).copysign(position)
+    }
+
+    /// Applies the curve to the length of a stick's (x, y) vector, keeping its direction.
+    pub fn apply_radial(&self, x: f64, y: f64) -> (f64, f64) {
+        let magnitude = x.hypot(y);
+        if magnitude == 0.0 {
+            return (0.0, 0.0);
+        }
+        let scale = self.apply(magnitude) / magnitude;
+        ((x * scale).clamp(-1.0, 1.0), (y * scale).clamp(-1.0, 1.0))
+    }
+
+    /// Checks the parameters, returning what is wrong with them.
+    pub fn validate(&self) -> Result<(), String> {
+        match self {
+            Curve::Power { exponent } if exponent.is_nan() || *exponent <= 0.0 => Err("`exponent` must be above 0".to_string()),
+            Curve::Exponential { rate } if !(-MAX_STEEPNESS..=MAX_STEEPNESS).contains(rate) => {
+                Err(format!("`rate` must be between -{} and {}", MAX_STEEPNESS, MAX_STEEPNESS))
+            },
+            Curve::Sigmoid { strength } if !(*strength > 0.0 && *strength <= MAX_STEEPNESS) => {
+                Err(format!("`strength` must be above 0 and at most {}", MAX_STEEPNESS))
+            },
+            Curve::Table { points } => {
+                if points.len() < 2 {
+                    return Err("`points` needs at least two points".to_string());
+                }
+                if points.iter().any(|&(x, y)| !(0.0..=1.0).contains(&x) || !(0.0..=1.0).contains(&y)) {
+                    return Err("`points` must lie between 0 and 1".to_string());
+                }
+                if points.windows(2).any(|pair| pair[0].0 >= pair[1].0) {
+                    return Err("`points` must be sorted by their input, without repeats".to_string());
+                }
+                if points[0].0 == 0.0 && points[0].1 != 0.0 {
+                    return Err("`points` must map an input of 0 to 0, so an axis at rest stays there".to_string());
+                }
+                Ok(())
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Looks `x` (0 to 1) up in `points`, sorted by input. Below the first point it interpolates from
+/// (0, 0), beyond the last one its output is kept.
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    let Some(upper) = points.iter().position(|&(input, _)| input >= x) else {
+        return points.last().map_or(x, |&(_, output)| output);
+    };
+    let lower = if upper == 0 { (0.0, 0.0) } else { points[upper - 1] };
+    let ((x0, y0), (x1, y1)) = (lower, points[upper]);
+    if x1 == x0 {
+        return y1;
+    }
+    y0 + (x - x0) / (x1 - x0) * (y1 - y0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-9
+    }
+
+    #[test]
+    fn deflection_round_trips() {
+        let stick = AbsInfo::new(0, -32768, 32767, 0, 0, 0);
+        let trigger = AbsInfo::new(0, 0, 1023, 0, 0, 0);
+        assert_eq!(deflection(0, &stick), 0.0);
+        assert_eq!(deflection(32767, &stick), 1.0);
+        assert_eq!(deflection(-32768, &stick), -1.0);
+        assert_eq!(deflection(1023, &trigger), 1.0);
+        for value in [-32768, -1000, 0, 1, 16000, 32767] {
+            assert_eq!(from_deflection(deflection(value, &stick), &stick), value);
+        }
+        assert_eq!(from_deflection(-0.5, &trigger), 0);
+        assert_eq!(from_deflection(2.0, &trigger), 1023);
+    }
+
+    #[test]
+    fn sticks_rest_in_the_middle_of_unsigned_ranges() {
+        let dualsense = AbsInfo::new(200, 0, 255, 0, 0, 0);
+        let stick = at_rest(AbsoluteAxisCode::ABS_X, &dualsense);
+        assert_eq!(stick.value(), 128);
+        assert_eq!(deflection(128, &stick), 0.0);
+        assert_eq!(deflection(255, &stick), 1.0);
+        assert_eq!(deflection(0, &stick), -1.0);
+        assert_eq!(from_deflection(0.0, &stick), 128);
+        assert_eq!(at_rest(AbsoluteAxisCode::ABS_Z, &dualsense).value(), 0);
+        assert_eq!(at_rest(AbsoluteAxisCode::ABS_X, &AbsInfo::new(5, -32768, 32767, 0, 0, 0)).value(), 0);
+    }
+
+    #[test]
+    fn axial_deadzone_stretches_what_is_left() {
+        let deadzone = Deadzone::new(0.1, 0.1);
+        assert_eq!(deadzone.apply(0.05), 0.0);
+        assert_eq!(deadzone.apply(-0.1), 0.0);
+        assert!(close(deadzone.apply(0.5), 0.5));
+        assert!(close(deadzone.apply(-0.3), -0.25));
+        assert_eq!(deadzone.apply(0.95), 1.0);
+        assert_eq!(deadzone.apply(-1.0), -1.0);
+    }
+
+    #[test]
+    fn radial_deadzone_keeps_the_direction() {
+        let deadzone = Deadzone::new(0.2, 0.0);
+        // Each axis alone would be inside an axial deadzone, together they are outside.
+        let (x, y) = deadzone.apply_radial(0.15, 0.15);
+        assert!(x > 0.0 && close(x, y));
+        assert!(close(x.hypot(y), (0.15f64.hypot(0.15) - 0.2) / 0.8));
+        assert_eq!(deadzone.apply_radial(0.1, -0.1), (0.0, 0.0));
+        let (x, y) = deadzone.apply_radial(0.0, -1.0);
+        assert!(close(x, 0.0) && close(y, -1.0));
+    }
+
+    #[test]
+    fn smoother_limits_rising_and_falling_separately() {
+        let mut smoother = Smoother::new(0.0, Some(2.0), Some(4.0));
+        let start = Instant::now();
+        smoother.set_target(1.0);
+        assert_eq!(smoother.update(start), 0.0);
+        assert_eq!(smoother.next_update(), Some(start + TICK_INTERVAL));
+        assert_eq!(smoother.update(start + Duration::from_millis(250)), 0.5);
+        assert_eq!(smoother.update(start + Duration::from_millis(600)), 1.0);
+        assert_eq!(smoother.next_update(), None);
+
+        // The idle time since doesn't count towards falling.
+        smoother.set_target(0.0);
+        assert_eq!(smoother.update(start + Duration::from_secs(10)), 1.0);
+        assert_eq!(smoother.update(start + Duration::from_millis(10_125)), 0.5);
+    }
+
+    #[test]
+    fn smoother_averages_towards_the_target() {
+        let mut smoother = Smoother::new(0.1, None, None);
+        let start = Instant::now();
+        smoother.set_target(1.0);
+        smoother.update(start);
+        let output = smoother.update(start + Duration::from_millis(100));
+        assert!((output - (1.0 - (-1.0f64).exp())).abs() < 1e-9, "{}", output);
+        // Close enough counts as caught up, so the ticking stops.
+        assert_eq!(smoother.update(start + Duration::from_secs(1)), 1.0);
+        assert_eq!(smoother.next_update(), None);
+    }
+
+    #[test]
+    fn curves_keep_the_ends_and_the_direction() {
+        let curves = [
+            Curve::Power { exponent: 2.0 },
+            Curve::Exponential { rate: 3.0 },
+            Curve::Exponential { rate: -3.0 },
+            Curve::Exponential { rate: 0.0 },
+            Curve::Sigmoid { strength: 2.0 },
+            Curve::Table { points: vec![(0.0, 0.0), (0.5, 0.2), (1.0, 1.0)] },
+            Curve::Table { points: vec![(0.2, 0.1), (1.0, 1.0)] },
+        ];
+        for curve in curves {
+            assert_eq!(curve.apply(0.0), 0.0, "{:?}", curve);
+            assert!(close(curve.apply(1.0), 1.0), "{:?}", curve);
+            assert!(close(curve.apply(-0.3), -curve.apply(0.3)), "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn curve_shapes() {
+        assert!(close(Curve::Power { exponent: 2.0 }.apply(0.5), 0.25));
+        assert!(close(Curve::Power { exponent: 0.5 }.apply(-0.25), -0.5));
+        assert!(Curve::Exponential { rate: 3.0 }.apply(0.5) < 0.5);
+        assert!(Curve::Exponential { rate: -3.0 }.apply(0.5) > 0.5);
+        assert!(close(Curve::Exponential { rate: 0.0 }.apply(0.3), 0.3));
+        let s_curve = Curve::Sigmoid { strength: 2.0 };
+        assert!(close(s_curve.apply(0.5), 0.5));
+        assert!(s_curve.apply(0.25) < 0.25 && s_curve.apply(0.75) > 0.75);
+    }
+
+    #[test]
+    fn table_interpolates_linearly() {
+        let points = [(0.2, 0.1), (0.6, 0.5), (1.0, 1.0)];
+        assert_eq!(interpolate(&points, 0.0), 0.0);
+        assert!(close(interpolate(&points, 0.1), 0.05));
+        assert!(close(interpolate(&points, 0.4), 0.3));
+        assert_eq!(interpolate(&points, 0.6), 0.5);
+        assert!(close(interpolate(&points, 0.8), 0.75));
+        assert_eq!(interpolate(&points[..2], 0.9), 0.5);
+    }
+
+    #[test]
+    fn radial_curve_keeps_the_direction() {
+        let (x, y) = Curve::Power { exponent: 2.0 }.apply_radial(0.3, -0.4);
+        assert!(close(x, 0.3 * 0.5) && close(y, -0.4 * 0.5));
+        assert_eq!(Curve::Power { exponent: 2.0 }.apply_radial(0.0, 0.0), (0.0, 0.0));
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        assert!(Curve::Table { points: vec![(0.0, 0.0)] }.validate().is_err());
+        assert!(Curve::Table { points: vec![(0.0, 0.0), (1.5, 1.0)] }.validate().is_err());
+        assert!(Curve::Table { points: vec![(0.5, 0.0), (0.5, 1.0)] }.validate().is_err());
+        assert!(Curve::Power { exponent: -1.0 }.validate().is_err());
+        assert!(Curve::Table { points: vec![(0.0, 0.1), (1.0, 1.0)] }.validate().is_err());
+        assert_eq!(Curve::Table { points: vec![(0.2, 0.1), (1.0, 1.0)] }.validate(), Ok(()));
+    }
+
+    #[test]
+    fn steepest_curves_stay_finite() {
+        let curves = [
+            Curve::Exponential { rate: MAX_STEEPNESS },
+            Curve::Exponential { rate: -MAX_STEEPNESS },
+            Curve::Sigmoid { strength: MAX_STEEPNESS },
+        ];
+        for curve in curves {
+            assert_eq!(curve.validate(), Ok(()));
+            for i in 0..=1000 {
+                let x = i as f64 / 1000.0;
+                assert!(curve.apply(x).is_finite(), "{:?} at {}", curve, x);
+            }
+            assert_eq!(curve.apply(1.0), 1.0, "{:?}", curve);
+            assert_eq!(curve.apply(-1.0), -1.0, "{:?}", curve);
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_steepness() {
+        assert!(Curve::Exponential { rate: 710.0 }.validate().is_err());
+        assert!(Curve::Exponential { rate: f64::NAN }.validate().is_err());
+        assert!(Curve::Sigmoid { strength: 2000.0 }.validate().is_err());
+        assert!(Curve::Sigmoid { strength: 0.0 }.validate().is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::discovery;
+use nix::errno::Errno;
+use nix::poll::{poll, PollFd, PollFlags, PollTimeout};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::{io, process, thread};
+use std::os::fd::{AsFd, BorrowedFd};
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Turns SIGINT/SIGTERM into a shutdown request that can be checked and waited on.
+///
+/// A second signal while shutting down exits immediately, in case something hangs. Hidden device
+/// nodes still get their permissions back then, nothing else is cleaned up (the kernel releases
+/// grabs and removes the virtual device once the process is gone).
+pub struct Shutdown {
+    requested: Arc<AtomicBool>,
+    /// Becomes (and stays) readable once a signal arrived, so it can be polled next to device fds.
+    wakeup: UnixStream,
+}
+
+impl Shutdown {
+    pub fn install() -> io::Result<Self> {
+        let requested = Arc::new(AtomicBool::new(false));
+        let (wakeup, notify) = UnixStream::pair()?;
+        notify.set_nonblocking(true)?;
+        for signal in [SIGINT, SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&requested))?;
+            signal_hook::low_level::pipe::register(signal, notify.try_clone()?)?;
+        }
+        let mut signals = Signals::new([SIGINT, SIGTERM])?;
+        thread::spawn(move || {
+            // The first signal is the regular shutdown request, handled by whoever waits on us.
+            if signals.forever().nth(1).is_some() {
+                eprintln!("Exiting immediately.");
+                discovery::restore_hidden_nodes();
+                process::exit(1);
+            }
+        });
+        Ok(Self { requested, wakeup })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+
+    /// Blocks until `fd` is readable, `timeout` passed or a shutdown was requested, whichever comes first.
+    pub fn wait(&self, fd: Option<BorrowedFd>, timeout: Option<Duration>) -> io::Result<()> {
+        let mut fds = vec![PollFd::new(self.wakeup.as_fd(), PollFlags::POLLIN)];
+        if let Some(fd) = fd {
+            fds.push(PollFd::new(fd, PollFlags::POLLIN));
+        }
+        let timeout = match timeout {
+            Some(timeout) => PollTimeout::try_from(timeout).unwrap_or(PollTimeout::MAX),
+            None => PollTimeout::NONE,
+        };
+        match poll(&mut fds, timeout) {
+            Ok(_) | Err(Errno::EINTR) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl AsFd for Shutdown {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.wakeup.as_fd()
+    }
+}
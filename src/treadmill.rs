@@ -0,0 +1,156 @@
+use crate::config::TreadmillConfig;
+use crate::shaping::{Curve, Smoother};
+use evdev::{AbsInfo, AbsoluteAxisCode, KeyCode};
+use std::time::Instant;
+
+/// How far below `run_threshold` the output has to drop before the sprint button is released again.
+const RUN_HYSTERESIS: f64 = 0.05;
+
+/// Turns the belt speed a treadmill sensor reports into how far the stick is pushed forward.
+///
+/// The speed is mapped from `min_speed..max_speed` onto 0..1, smoothed with an exponential moving
+/// average and then limited to the ramp rates, so the character speeds up and slows down gradually.
+pub struct Treadmill {
+    axis: AbsoluteAxisCode,
+    stick: AbsoluteAxisCode,
+    invert: bool,
+    min_speed: Option<i32>,
+    max_speed: Option<i32>,
+    run_threshold: f64,
+    sprint_button: Option<KeyCode>,
+    curve: Option<Curve>,
+    /// The stick's sideways axis, when the other sources steer.
+    heading: Option<AbsoluteAxisCode>,
+
+    /// Follows the belt speed (0 to 1), its time constant and rate limits being the smoothing and ramps.
+    smoother: Smoother,
+    running: bool,
+}
+
+impl Treadmill {
+    /// Expects a validated config.
+    pub fn new(config: &TreadmillConfig) -> Option<Self> {
+        Some(Self {
+            axis: config.axis.parse().ok()?,
+            stick: config.stick().parse().ok()?,
+            invert: config.invert,
+            min_speed: config.min_speed,
+            max_speed: config.max_speed,
+            run_threshold: config.run_threshold(),
+            sprint_button: config.sprint_button.as_deref().map(str::parse).transpose().ok()?,
+            curve: config.curve.clone(),
+            heading: if config.heading { Some(config.strafe_axis().parse().ok()?) } else { None },
+            smoother: Smoother::new(config.smoothing(), config.ramp_up, config.ramp_down),
+            running: false,
+        })
+    }
+
+    /// The sensor axis reporting the belt speed.
+    pub fn axis(&self) -> AbsoluteAxisCode {
+        self.axis
+    }
+
+    /// The virtual axis pushed forward.
+    pub fn stick(&self) -> AbsoluteAxisCode {
+        self.stick
+    }
+
+    /// With heading on, the stick's sideways axis: the treadmill only sets how far the stick is
+    /// pushed, the other sources where to.
+    pub fn heading(&self) -> Option<AbsoluteAxisCode> {
+        self.heading
+    }
+
+    pub fn sprint_button(&self) -> Option<KeyCode> {
+        self.sprint_button
+    }
+
+    /// Takes a new speed reading, `range` being the sensor axis's range.
+    pub fn set_speed(&mut self, value: i32, range: Option<&AbsInfo>) {
+        let min = self.min_speed.or(range.map(AbsInfo::minimum)).unwrap_or(0) as f64;
+        let max = self.max_speed.or(range.map(AbsInfo::maximum)).unwrap_or(i32::MAX) as f64;
+        let speed = if max > min { ((value as f64 - min) / (max - min)).clamp(0.0, 1.0) } else { 0.0 };
+        self.smoother.set_target(self.curve.as_ref().map_or(speed, |curve| curve.apply(speed)));
+    }
+
+    /// Moves the output on to `now`. Returns the stick deflection (-1 to 1) and whether the sprint
+    /// button should be down.
+    pub fn update(&mut self, now: Instant) -> (f64, bool) {
+        let output = self.smoother.update(now);
+        let threshold = if self.running { self.run_threshold - RUN_HYSTERESIS } else { self.run_threshold };
+        self.running = output >= threshold;
+
+        let deflection = if self.invert { output } else { -output };
+        (deflection, self.running && self.sprint_button.is_some())
+    }
+
+    /// When the output next needs to move, `None` once it caught up with the belt.
+    pub fn next_update(&self) -> Option<Instant> {
+        self.smoother.next_update()
+    }
+
+    /// Forgets the belt speed, e.g. when the sensor disconnects.
+    pub fn reset(&mut self) {
+        self.smoother.reset();
+        self.running = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn from_toml(text: &str) -> Treadmill {
+        Treadmill::new(&toml::from_str(text).expect("parses")).expect("valid")
+    }
+
+    #[test]
+    fn maps_the_speed_range_onto_the_stick() {
+        let mut treadmill = from_toml("axis = \"ABS_X\"\nmin_speed = 100\nmax_speed = 300\nsmoothing = 0.0");
+        let now = Instant::now();
+        for (value, deflection) in [(50, 0.0), (200, -0.5), (300, -1.0), (1000, -1.0)] {
+            treadmill.set_speed(value, None);
+            assert_eq!(treadmill.update(now).0, deflection, "{}", value);
+        }
+        let range = AbsInfo::new(0, 0, 1000, 0, 0, 0);
+        let mut treadmill = from_toml("axis = \"ABS_X\"\nsmoothing = 0.0\ninvert = true");
+        treadmill.set_speed(250, Some(&range));
+        assert_eq!(treadmill.update(now).0, 0.25);
+    }
+
+    #[test]
+    fn ramps_up_over_time() {
+        let mut treadmill = from_toml("axis = \"ABS_X\"\nsmoothing = 0.0\nramp_up = 2.0");
+        let range = AbsInfo::new(0, 0, 100, 0, 0, 0);
+        let start = Instant::now();
+        treadmill.set_speed(100, Some(&range));
+        assert_eq!(treadmill.update(start).0, 0.0);
+        assert_eq!(treadmill.update(start + Duration::from_millis(250)).0, -0.5);
+        assert!(treadmill.next_update().is_some());
+        assert_eq!(treadmill.update(start + Duration::from_secs(1)).0, -1.0);
+        assert_eq!(treadmill.next_update(), None);
+    }
+
+    #[test]
+    fn sprints_with_hysteresis() {
+        let mut treadmill = from_toml("axis = \"ABS_X\"\nsmoothing = 0.0\nrun_threshold = 0.8\nsprint_button = \"BTN_THUMBL\"");
+        let range = AbsInfo::new(0, 0, 100, 0, 0, 0);
+        let now = Instant::now();
+        for (speed, sprinting) in [(79, false), (80, true), (76, true), (74, false), (78, false)] {
+            treadmill.set_speed(speed, Some(&range));
+            assert_eq!(treadmill.update(now).1, sprinting, "{}", speed);
+        }
+    }
+
+    #[test]
+    fn reset_stops_at_once() {
+        let mut treadmill = from_toml("axis = \"ABS_X\"\nramp_down = 0.5");
+        let range = AbsInfo::new(0, 0, 100, 0, 0, 0);
+        treadmill.set_speed(100, Some(&range));
+        treadmill.update(Instant::now());
+        treadmill.reset();
+        assert_eq!(treadmill.next_update(), None);
+        assert_eq!(treadmill.update(Instant::now()), (0.0, false));
+    }
+}